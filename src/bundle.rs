@@ -0,0 +1,75 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+use crate::error::{CavempvError, Result};
+use crate::logging;
+use crate::settings::Settings;
+
+/// Daily log files going in, newest first
+const RECENT_LOGS: usize = 3;
+
+/// What goes in besides files, collected on the runtime beforehand
+pub struct Snapshot {
+    /// Resolved config with the token masked
+    pub config: String,
+    pub status: String,
+    pub mpv: String,
+}
+
+/// Zips the snapshot with the recent logs, the state file and the transition log,
+/// whichever of those exist. Blocking, returns the names of the entries.
+/// An existing `target` is never overwritten.
+pub fn export(s: &Settings, snapshot: Snapshot, target: &Path) -> Result<Vec<String>> {
+    let mut zip = ZipWriter::new(File::create_new(target)?);
+    let options = SimpleFileOptions::default();
+    let mut entries = Vec::new();
+
+    let generated = [
+        ("config.json", snapshot.config),
+        ("status.json", snapshot.status),
+        ("mpv.json", snapshot.mpv),
+    ];
+    for (name, contents) in generated {
+        zip.start_file(name, options).map_err(failed)?;
+        zip.write_all(contents.as_bytes())?;
+        entries.push(name.to_string());
+    }
+
+    let mut files: Vec<(String, PathBuf)> = logging::newest_logs(Path::new(&s.log_dir))
+        .into_iter()
+        .filter(|path| logging::is_daily_log(path) && path != target)
+        .take(RECENT_LOGS)
+        .map(|path| (format!("logs/{}", file_name(&path)), path))
+        .collect();
+    let rotated = s.transition_log.as_ref().map(|log| format!("{log}.1"));
+    for path in [s.state_file.clone(), s.transition_log.clone(), rotated].into_iter().flatten() {
+        let path = PathBuf::from(path);
+        files.push((file_name(&path), path));
+    }
+    for (name, path) in files {
+        // Not written yet, nothing to add
+        let Ok(contents) = std::fs::read(&path) else {
+            continue;
+        };
+        zip.start_file(name.as_str(), options).map_err(failed)?;
+        zip.write_all(&contents)?;
+        entries.push(name);
+    }
+
+    zip.finish().map_err(failed)?;
+    Ok(entries)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn failed(e: zip::result::ZipError) -> CavempvError {
+    CavempvError::Bundle(e.to_string())
+}
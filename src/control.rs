@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libmpv::Mpv;
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, UnixListener},
+    sync::{Mutex, mpsc},
+    time::Instant,
+};
+
+use crate::error::Result;
+use crate::{ButtonState, Command, diagnostics, media_info};
+use crate::settings::{self, Settings};
+use crate::state::{Source, State};
+use crate::{bundle, logging, selftest, thumbnails};
+
+/// Live mpv properties a support bundle snapshots
+const BUNDLE_PROPERTIES: &[&str] = &[
+    "path",
+    "playback-time",
+    "volume",
+    "mute",
+    "speed",
+    "aid",
+    "hwdec-current",
+    "display-names",
+    "audio-device",
+];
+
+/// Screenshots are refused more often than this
+const SCREENSHOT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// One fragment as `list` reports it
+#[derive(Serialize)]
+struct Listed<'a> {
+    index: usize,
+    name: String,
+    #[serde(rename = "static")]
+    static_: String,
+    intro: bool,
+    fadeout: bool,
+    /// Seconds looped over within the static, the whole file otherwise
+    ab_loop: Option<(f64, f64)>,
+    tags: &'a [String],
+}
+
+/// Compares a presented token without bailing out at the first wrong byte,
+/// so timing gives away no more than the length
+pub fn token_matches(given: Option<&str>, expected: &str) -> bool {
+    given.is_some_and(|given| {
+        given.len() == expected.len()
+            && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    })
+}
+
+/// Commands any text source may send, `None` for lines that aren't one
+pub fn parse(line: &str) -> Option<std::result::Result<Command, String>> {
+    let line = line.trim();
+    let parsed = match line.split_once(' ').unwrap_or((line, "")) {
+        ("next", "") => Ok(Command::Next),
+        ("prev", "") => Ok(Command::Prev),
+        ("sleep", "") => Ok(Command::Sleep),
+        ("volume", "up") => Ok(Command::VolumeUp),
+        ("volume", "down") => Ok(Command::VolumeDown),
+        ("volume", level) => level.parse().map(Command::SetVolume).map_err(|e| e.to_string()),
+        ("goto", index) => index.parse().map(Command::GoTo).map_err(|e| e.to_string()),
+        ("skip", steps) => steps.parse().map(Command::Skip).map_err(|e| e.to_string()),
+        ("speed", "reset") => Ok(Command::ResetSpeed),
+        ("speed", speed) => speed.parse().map(Command::SetSpeed).map_err(|e| e.to_string()),
+        ("buttons", "start_only") => Ok(Command::ForceButtonState(Some(ButtonState::StartOnly))),
+        ("buttons", "both") => Ok(Command::ForceButtonState(Some(ButtonState::Both))),
+        ("buttons", "off") => Ok(Command::ForceButtonState(Some(ButtonState::Off))),
+        ("buttons", "auto") => Ok(Command::ForceButtonState(None)),
+        ("audio", "next") => Ok(Command::NextAudioDevice),
+        ("mute", "") => Ok(Command::ToggleMute),
+        ("reset", "") => Ok(Command::Reset),
+        ("restart", "") => Ok(Command::Restart),
+        ("awake", "hold") => Ok(Command::HoldAwake(true)),
+        ("awake", "release") => Ok(Command::HoldAwake(false)),
+        ("identify", "") => Ok(Command::Identify),
+        ("macro", name) if !name.is_empty() => Ok(Command::RunMacro(name.to_string())),
+        _ => return None,
+    };
+    Some(parsed)
+}
+
+/// Line based control interface, served on a unix socket and/or TCP.
+/// Accepts `next`, `prev`, `sleep`, `goto <index>`, `skip <steps>`, `reset`, `restart`,
+/// `volume up|down|<level>`, `mute`, `speed <factor>|reset`, `buttons start_only|both|off|auto`,
+/// `audio next`, `fullscreen` (if allowed), `identify`, `awake hold|release`, `macro <name>`,
+/// `pack <dir>`, `playlist <name>`, `list`, `screenshot`, `mediainfo`, `status`, `config`,
+/// `thumbnails <dir>`, `exportbundle <path>`, `flushlogs [rotate]` and `selftest`,
+/// answers every line with a single line.
+/// In maintenance mode `getprop <name>` and `setprop <name> <value>` reach mpv directly.
+/// When a token is configured the first line has to be `AUTH <token>`.
+/// `reset` only rereads the playlist and serial ports, any other config change takes `restart`.
+#[derive(Clone)]
+pub struct Control {
+    pub intake: mpsc::Sender<(Source, Command)>,
+    pub state: Arc<Mutex<State>>,
+    pub token: Option<String>,
+    pub settings: Arc<Settings>,
+    pub mpv: Arc<Mpv>,
+    pub last_screenshot: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Control {
+    /// `authenticate` can be turned off here, since the socket is already
+    /// guarded by filesystem permissions
+    pub async fn serve_unix(self, path: String, authenticate: bool) -> Result<()> {
+        // A stale socket from a previous run would make bind fail
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        info!("Control socket listening on {path}");
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(self.clone().handle(stream, authenticate));
+        }
+    }
+
+    pub async fn serve_tcp(self, addr: String) -> Result<()> {
+        let listener = TcpListener::bind(&addr).await?;
+        info!("Control listening on {addr}");
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            info!("Control connection from {peer}");
+            tokio::spawn(self.clone().handle(stream, true));
+        }
+    }
+
+    async fn handle<S>(self, stream: S, authenticate: bool)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, mut write) = tokio::io::split(stream);
+        let mut lines = BufReader::new(read).lines();
+
+        if let Some(token) = self.token.as_deref().filter(|_| authenticate) {
+            let authorized = matches!(
+                lines.next_line().await,
+                Ok(Some(line)) if token_matches(line.trim().strip_prefix("AUTH "), token)
+            );
+            let reply: &[u8] = if authorized {
+                b"ok\n"
+            } else {
+                warn!("Rejected unauthenticated control connection");
+                b"error: unauthorized\n"
+            };
+            if write.write_all(reply).await.is_err() || !authorized {
+                return;
+            }
+        }
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let line = line.trim();
+            let reply = match parse(line) {
+                Some(Ok(command)) => self.enqueue(command).await,
+                Some(Err(e)) => format!("error: {e}"),
+                None => self.answer(line).await,
+            };
+            if let Err(e) = write.write_all(format!("{reply}\n").as_bytes()).await {
+                error!("Failed to answer on control connection: {e:?}");
+                break;
+            }
+        }
+    }
+
+    /// Lines that aren't plain commands, answered right here
+    async fn answer(&self, line: &str) -> String {
+        match line.split_once(' ').unwrap_or((line, "")) {
+            ("fullscreen", "") if self.settings.allow_fullscreen_toggle => {
+                self.enqueue(Command::ToggleFullscreen).await
+            }
+            // Validated here for a useful reply, playback loads it again when it switches
+            ("pack", dir) if !dir.is_empty() => match settings::load_pack(Path::new(dir)) {
+                Ok(_) => self.enqueue(Command::LoadPack(dir.to_string())).await,
+                Err(e) => format!("error: {e}"),
+            },
+            ("playlist", name) if !name.is_empty() => match self.settings.named_playlist(name) {
+                Ok(_) => self.enqueue(Command::SwitchPlaylist(name.to_string())).await,
+                Err(e) => format!("error: {e}"),
+            },
+            ("list", "") => self.list().await,
+            ("screenshot", "") => self.screenshot().await,
+            ("mediainfo", "") => serde_json::to_string(&media_info(&self.mpv))
+                .unwrap_or_else(|e| format!("error: {e}")),
+            ("status", "") => serde_json::to_string(&*self.state.lock().await)
+                .unwrap_or_else(|e| format!("error: {e}")),
+            ("config", "") => serde_json::to_string(&self.settings.masked())
+                .unwrap_or_else(|e| format!("error: {e}")),
+            ("thumbnails", dir) if !dir.is_empty() => self.thumbnails(dir).await,
+            ("exportbundle", path) if !path.is_empty() => self.export_bundle(path).await,
+            ("flushlogs", rotate @ ("" | "rotate")) => match logging::flush(rotate == "rotate") {
+                Ok(Some(closed)) => closed.to_string_lossy().into_owned(),
+                Ok(None) => "ok".to_string(),
+                Err(e) => format!("error: {e}"),
+            },
+            ("selftest", "") => {
+                serde_json::to_string(&selftest::run(&self.settings, &self.state).await)
+                    .unwrap_or_else(|e| format!("error: {e}"))
+            }
+            ("getprop", name) if self.settings.maintenance_enabled && !name.is_empty() => self
+                .mpv
+                .get_property::<String>(name)
+                .unwrap_or_else(|e| format!("error: {e:?}")),
+            ("setprop", property) if self.settings.maintenance_enabled => {
+                self.set_property(property)
+            }
+            _ => format!("error: unknown command {line}"),
+        }
+    }
+
+    /// Takes `<name> <value>`, as long as the name is on the writable list
+    fn set_property(&self, property: &str) -> String {
+        let Some((name, value)) = property.split_once(' ') else {
+            return "error: expected setprop <name> <value>".to_string();
+        };
+        if !self.settings.maintenance_writable.iter().any(|writable| writable == name) {
+            warn!("Refused to set {name}, it isn't writable");
+            return format!("error: {name} isn't writable");
+        }
+        match self.mpv.set_property(name, value.trim()) {
+            Ok(()) => {
+                info!("Set {name} to {value} in maintenance mode");
+                "ok".to_string()
+            }
+            Err(e) => format!("error: {e:?}"),
+        }
+    }
+
+    /// Paths come out relative to `media_root`
+    async fn list(&self) -> String {
+        let playlist = self.state.lock().await.playlist.clone();
+        let root = self.settings.media_dir().ok();
+        let listed: Vec<Listed> = playlist
+            .iter()
+            .enumerate()
+            .map(|(index, fragment)| Listed {
+                index,
+                name: fragment.display_name(),
+                static_: root
+                    .as_deref()
+                    .and_then(|root| Path::new(&fragment.static_).strip_prefix(root).ok())
+                    .map_or_else(
+                        || fragment.static_.clone(),
+                        |relative| relative.to_string_lossy().into_owned(),
+                    ),
+                intro: fragment.intro.is_some(),
+                fadeout: fragment.fadeout.is_some(),
+                ab_loop: fragment.ab_loop(),
+                tags: fragment.tags.as_deref().unwrap_or_default(),
+            })
+            .collect();
+        serde_json::to_string(&listed).unwrap_or_else(|e| format!("error: {e}"))
+    }
+
+    /// Saves what's on screen into the temp dir and answers with the path
+    async fn screenshot(&self) -> String {
+        if self.state.lock().await.disk_low {
+            return "error: disk space is low".to_string();
+        }
+        {
+            let mut last = self.last_screenshot.lock().await;
+            if let Some(wait) = last
+                .map(|at| SCREENSHOT_INTERVAL.saturating_sub(at.elapsed()))
+                .filter(|wait| !wait.is_zero())
+            {
+                return format!("error: next screenshot in {wait:?}");
+            }
+            *last = Some(Instant::now());
+        }
+
+        let path = std::env::temp_dir().join(format!(
+            "cavempv-{}.png",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis())
+        ));
+        let mpv = self.mpv.clone();
+        let target = path.to_string_lossy().into_owned();
+        // mpv encodes the image before returning, keep that off the runtime
+        match tokio::task::spawn_blocking(move || {
+            mpv.command("screenshot-to-file", &[target.as_str(), "video"])
+                .map_err(|e| format!("{e:?}"))
+        })
+        .await
+        {
+            Ok(Ok(())) => {
+                info!("Saved screenshot {}", path.display());
+                path.to_string_lossy().into_owned()
+            }
+            Ok(Err(e)) => format!("error: {e}"),
+            Err(e) => format!("error: {e}"),
+        }
+    }
+
+    /// Runs off the runtime with its own mpv, playback carries on meanwhile
+    async fn thumbnails(&self, dir: &str) -> String {
+        if self.state.lock().await.disk_low {
+            return "error: disk space is low".to_string();
+        }
+        let settings = self.settings.clone();
+        let dir = PathBuf::from(dir);
+        match tokio::task::spawn_blocking(move || thumbnails::generate(&settings.playlist, &dir))
+            .await
+        {
+            Ok(Ok(files)) => serde_json::to_string(&files).unwrap_or_else(|e| format!("error: {e}")),
+            Ok(Err(e)) => format!("error: {e}"),
+            Err(e) => format!("error: {e}"),
+        }
+    }
+
+    /// Zips up what support asks for, answering with the entries it got
+    async fn export_bundle(&self, path: &str) -> String {
+        let properties: HashMap<&str, String> = BUNDLE_PROPERTIES
+            .iter()
+            .map(|&name| (name, self.mpv.get_property::<String>(name).unwrap_or_default()))
+            .collect();
+        let startup: HashMap<&str, String> =
+            diagnostics(&self.settings, &self.mpv).into_iter().collect();
+        let mpv = serde_json::json!({
+            "media": media_info(&self.mpv),
+            "properties": properties,
+            "startup": startup,
+        });
+        let snapshot = bundle::Snapshot {
+            config: serde_json::to_string_pretty(&self.settings.masked())
+                .unwrap_or_else(|e| format!("error: {e}")),
+            status: serde_json::to_string_pretty(&*self.state.lock().await)
+                .unwrap_or_else(|e| format!("error: {e}")),
+            mpv: mpv.to_string(),
+        };
+
+        let settings = self.settings.clone();
+        let target = PathBuf::from(path);
+        match tokio::task::spawn_blocking(move || bundle::export(&settings, snapshot, &target))
+            .await
+        {
+            Ok(Ok(entries)) => {
+                info!("Exported a support bundle to {path}");
+                serde_json::to_string(&entries).unwrap_or_else(|e| format!("error: {e}"))
+            }
+            Ok(Err(e)) => format!("error: {e}"),
+            Err(e) => format!("error: {e}"),
+        }
+    }
+
+    async fn enqueue(&self, command: Command) -> String {
+        match self.intake.send((Source::Socket, command)).await {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("error: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_has_to_match_exactly() {
+        assert!(token_matches(Some("s3cret"), "s3cret"));
+        assert!(!token_matches(Some("s3creT"), "s3cret"));
+        assert!(!token_matches(Some("s3cre"), "s3cret"));
+        assert!(!token_matches(None, "s3cret"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use nix::sys::statvfs::statvfs;
+use tokio::sync::Mutex;
+
+use crate::logging;
+use crate::settings::Settings;
+use crate::state::State;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Log files left once space runs low, today's included
+const LOW_KEEP_LOGS: usize = 2;
+
+/// Megabytes free on the volume holding `path`
+// The casts only do something on 32-bit boards
+#[allow(clippy::unnecessary_cast)]
+fn free_mb(path: &Path) -> nix::Result<u64> {
+    let stat = statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64 / (1024 * 1024))
+}
+
+/// Daily log files past the newest `keep`, what pruning deletes.
+/// Anything else in the directory, a state file for example, is left alone.
+fn prunable(logs: Vec<PathBuf>, keep: usize) -> Vec<PathBuf> {
+    logs.into_iter()
+        .filter(|path| logging::is_daily_log(path))
+        .skip(keep)
+        .collect()
+}
+
+/// Checks the log and media volumes every minute against `min_free_mb`.
+/// Below it old logs get pruned and `disk_low` stops screenshots and thumbnails.
+pub async fn watch(s: Arc<Settings>, min_free_mb: u64, state: Arc<Mutex<State>>) {
+    let log_dir = PathBuf::from(&s.log_dir);
+    let volumes: Vec<PathBuf> = [Some(log_dir.clone()), s.media_dir().ok()]
+        .into_iter()
+        .flatten()
+        .collect();
+    let mut ticks = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        ticks.tick().await;
+        let lowest = volumes
+            .iter()
+            .filter_map(|volume| match free_mb(volume) {
+                Ok(free) => Some((free, volume)),
+                Err(e) => {
+                    error!("Can't check free space on {}: {e}", volume.display());
+                    None
+                }
+            })
+            .min();
+        let low = lowest.is_some_and(|(free, _)| free < min_free_mb);
+        let was_low = std::mem::replace(&mut state.lock().await.disk_low, low);
+
+        match lowest {
+            Some((free, volume)) if low => {
+                warn!(
+                    "LOW DISK SPACE: {free}MB left on {}, below {min_free_mb}MB, \
+                     screenshots and thumbnails are off",
+                    volume.display()
+                );
+                for old in prunable(logging::newest_logs(&log_dir), LOW_KEEP_LOGS) {
+                    match std::fs::remove_file(&old) {
+                        Ok(()) => info!("Pruned {}", old.display()),
+                        Err(e) => error!("Failed to prune {}: {e}", old.display()),
+                    }
+                }
+            }
+            _ if was_low => info!("Disk space is back above {min_free_mb}MB"),
+            _ => {}
+        }
+    }
+}
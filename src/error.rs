@@ -0,0 +1,43 @@
+use thiserror::Error;
+
+/// Failures of the core functions, `anyhow` only wraps these at the top of `main`
+#[derive(Debug, Error)]
+pub enum CavempvError {
+    #[error("failed to load config: {0}")]
+    ConfigLoad(#[from] config::ConfigError),
+    #[error("playlist is empty")]
+    PlaylistEmpty,
+    #[error("no playlist named {0}")]
+    PlaylistUnknown(String),
+    #[error("default_playlist has to pick one of the {0} playlists")]
+    PlaylistAmbiguous(usize),
+    #[error("media file {0} does not exist")]
+    MediaMissing(String),
+    #[error("{0} media files don't match their media.sha256")]
+    MediaCorrupt(usize),
+    #[error("failed to set up logging: {0}")]
+    Logging(String),
+    #[error("failed to open serial port {path}: {source}")]
+    SerialOpen {
+        path: String,
+        source: tokio_serial::Error,
+    },
+    #[error("no ack from firmware on {path} within {timeout_ms}ms")]
+    SerialSelfTest { path: String, timeout_ms: u64 },
+    /// libmpv errors aren't `Send`, so only their description is kept
+    #[error("failed to start mpv: {0}")]
+    MpvInit(String),
+    #[error("mpv {command} failed: {reason}")]
+    MpvCommand { command: String, reason: String },
+    #[error("thumbnail failed: {0}")]
+    Thumbnail(String),
+    #[error("support bundle failed: {0}")]
+    Bundle(String),
+    #[cfg(feature = "gpio")]
+    #[error("GPIO failed: {0}")]
+    Gpio(#[from] rppal::gpio::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, CavempvError>;
@@ -0,0 +1,70 @@
+use std::path::Path;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::unix::pipe,
+    sync::mpsc,
+};
+
+use crate::Command;
+use crate::control;
+use crate::error::Result;
+use crate::state::Source;
+
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+
+/// Feeds commands written into a FIFO, one per line, into the intake.
+/// The FIFO is created if missing and reopened whenever reading fails.
+pub async fn run(path: String, intake: mpsc::Sender<(Source, Command)>) {
+    loop {
+        let receiver = match open(&path).await {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                error!("[{path}] Failed to open: {e:?}");
+                tokio::time::sleep(REOPEN_DELAY).await;
+                continue;
+            }
+        };
+        info!("Reading commands from {path}");
+
+        let mut lines = BufReader::new(receiver).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => match control::parse(&line) {
+                    Some(Ok(command)) => {
+                        if intake.send((Source::Fifo, command)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Err(e)) => warn!("[{path}] Invalid command {line:?}: {e}"),
+                    None if line.trim().is_empty() => {}
+                    None => warn!("[{path}] Unknown command {line:?}"),
+                },
+                Ok(None) => {
+                    warn!("[{path}] Closed, reopening");
+                    break;
+                }
+                Err(e) => {
+                    error!("[{path}] Failed to read: {e:?}");
+                    tokio::time::sleep(REOPEN_DELAY).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn open(path: &str) -> Result<pipe::Receiver> {
+    if !Path::new(path).exists() {
+        // Same as the mkfifo tool, the umask takes it from there
+        mkfifo(path, Mode::from_bits_truncate(0o666)).map_err(std::io::Error::from)?;
+    }
+    // Holding a write end as well keeps reads from hitting EOF whenever a writer leaves
+    Ok(pipe::OpenOptions::new()
+        .read_write(true)
+        .open_receiver(path)?)
+}
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use log::{info, warn};
+use rppal::gpio::{Gpio, OutputPin};
+
+use crate::error::Result;
+use crate::settings::{Fragment, Settings};
+use crate::supervise;
+
+/// What switching a relay takes from its pin, so it can be checked without a Pi
+pub trait Relay: Send + 'static {
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+}
+
+impl Relay for OutputPin {
+    fn set_high(&mut self) {
+        OutputPin::set_high(self);
+    }
+
+    fn set_low(&mut self) {
+        OutputPin::set_low(self);
+    }
+}
+
+/// Gets hold of a pin as an output, low to start with
+type Claim<P> = Arc<dyn Fn(u8) -> Result<P> + Send + Sync>;
+
+/// Relay pins that follow the fragment being played, claimed low at startup for every
+/// playlist in the config. A pin only a content pack asks for gets claimed when first driven.
+/// Clones drive the same pins, which all go low once the last one is dropped.
+pub struct Relays<P: Relay = OutputPin> {
+    claim: Claim<P>,
+    pins: Arc<Mutex<Pins<P>>>,
+}
+
+struct Pins<P: Relay>(BTreeMap<u8, P>);
+
+impl<P: Relay> Drop for Pins<P> {
+    fn drop(&mut self) {
+        self.0.values_mut().for_each(P::set_low);
+    }
+}
+
+impl<P: Relay> Clone for Relays<P> {
+    fn clone(&self) -> Self {
+        Relays {
+            claim: self.claim.clone(),
+            pins: self.pins.clone(),
+        }
+    }
+}
+
+impl Relays {
+    pub fn new(s: &Settings) -> Result<Self> {
+        let gpio = Gpio::new()?;
+        let fragments = s.playlist.iter().chain(s.playlists.values().flatten());
+        Relays::with(fragments, move |pin| Ok(gpio.get(pin)?.into_output_low()))
+    }
+}
+
+impl<P: Relay> Relays<P> {
+    fn with<'a>(
+        fragments: impl IntoIterator<Item = &'a Fragment>,
+        claim: impl Fn(u8) -> Result<P> + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let mut pins = BTreeMap::new();
+        for fragment in fragments {
+            for pin in fragment.gpio_high.iter().flatten() {
+                if !pins.contains_key(pin) {
+                    pins.insert(*pin, claim(*pin)?);
+                }
+            }
+        }
+        if !pins.is_empty() {
+            info!("Driving relays on pins {:?}", pins.keys());
+        }
+        Ok(Relays {
+            claim: Arc::new(claim),
+            pins: Arc::new(Mutex::new(Pins(pins))),
+        })
+    }
+
+    /// Drives `high` high and every other relay low
+    pub fn drive(&mut self, high: &[u8]) {
+        let mut pins = self.pins.lock().unwrap_or_else(PoisonError::into_inner);
+        for number in high {
+            if pins.0.contains_key(number) {
+                continue;
+            }
+            match (self.claim)(*number) {
+                Ok(pin) => {
+                    info!("Driving a relay on pin {number} too");
+                    pins.0.insert(*number, pin);
+                }
+                Err(e) => warn!("Can't drive a relay on pin {number}: {e}"),
+            }
+        }
+        for (number, pin) in &mut pins.0 {
+            if high.contains(number) {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.drive(&[]);
+    }
+
+    /// A crash, restart or SIGTERM exits without dropping anything, so the pins get reset
+    /// on the way out for as long as some clone is still around
+    pub fn reset_on_exit(&self) {
+        let (claim, pins) = (self.claim.clone(), Arc::downgrade(&self.pins));
+        supervise::on_exit(move || {
+            if let Some(pins) = pins.upgrade() {
+                let claim = claim.clone();
+                Relays { claim, pins }.reset();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::error::CavempvError;
+
+    #[derive(Clone, Default)]
+    struct MockPin(Arc<AtomicBool>);
+
+    impl MockPin {
+        fn is_high(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Relay for MockPin {
+        fn set_high(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+
+        fn set_low(&mut self) {
+            self.0.store(false, Ordering::SeqCst);
+        }
+    }
+
+    type Claimed = Arc<Mutex<BTreeMap<u8, MockPin>>>;
+
+    fn fragment(pins: &[u8]) -> Fragment {
+        serde_json::from_value(serde_json::json!({ "static": "a.mp4", "gpio_high": pins }))
+            .expect("a valid fragment")
+    }
+
+    /// Relays on mock pins, with a look at every pin they claimed
+    fn relays(fragments: &[Fragment]) -> (Relays<MockPin>, Claimed) {
+        let claimed = Claimed::default();
+        let seen = claimed.clone();
+        let relays = Relays::with(fragments, move |pin| {
+            let mock = MockPin::default();
+            seen.lock().unwrap().insert(pin, mock.clone());
+            Ok(mock)
+        })
+        .unwrap();
+        (relays, claimed)
+    }
+
+    fn high(claimed: &Claimed) -> Vec<u8> {
+        let claimed = claimed.lock().unwrap();
+        claimed.iter().filter(|(_, pin)| pin.is_high()).map(|(number, _)| *number).collect()
+    }
+
+    #[test]
+    fn claims_every_configured_pin_low() {
+        let (_relays, claimed) = relays(&[fragment(&[2, 3]), fragment(&[3, 4])]);
+        assert_eq!(claimed.lock().unwrap().keys().copied().collect::<Vec<_>>(), [2, 3, 4]);
+        assert!(high(&claimed).is_empty());
+    }
+
+    #[test]
+    fn drives_only_the_fragment_pins() {
+        let (mut relays, claimed) = relays(&[fragment(&[2, 3]), fragment(&[4])]);
+        relays.drive(&[2, 3]);
+        assert_eq!(high(&claimed), [2, 3]);
+        relays.drive(&[4]);
+        assert_eq!(high(&claimed), [4]);
+        relays.reset();
+        assert!(high(&claimed).is_empty());
+    }
+
+    #[test]
+    fn claims_an_unknown_pin_when_driven() {
+        let (mut relays, claimed) = relays(&[fragment(&[2])]);
+        relays.drive(&[7]);
+        assert_eq!(high(&claimed), [7]);
+    }
+
+    #[test]
+    fn skips_a_pin_that_cant_be_claimed() {
+        let mut relays = Relays::with(&[fragment(&[2])], |pin| match pin {
+            2 => Ok(MockPin::default()),
+            _ => Err(CavempvError::Io(std::io::Error::other("no such pin"))),
+        })
+        .unwrap();
+        relays.drive(&[2, 9]);
+        assert_eq!(relays.pins.lock().unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn dropping_the_last_clone_switches_everything_off() {
+        let (mut relays, claimed) = relays(&[fragment(&[2])]);
+        let clone = relays.clone();
+        relays.drive(&[2]);
+        drop(relays);
+        assert_eq!(high(&claimed), [2]);
+        drop(clone);
+        assert!(high(&claimed).is_empty());
+    }
+}
@@ -0,0 +1,24 @@
+//! Command injection for integration tests, compiled in only with the `test-hooks` feature
+//! so release builds carry none of it.
+
+use std::sync::OnceLock;
+
+use tokio::sync::mpsc;
+
+use crate::Command;
+use crate::state::Source;
+
+static INTAKE: OnceLock<mpsc::Sender<(Source, Command)>> = OnceLock::new();
+
+/// Hooks into the same intake the serial ports feed
+pub fn install(intake: mpsc::Sender<(Source, Command)>) {
+    let _ = INTAKE.set(intake);
+}
+
+/// Queues `command` as if it came from a port, false before `install` or after shutdown
+pub async fn inject_command(command: Command) -> bool {
+    match INTAKE.get() {
+        Some(intake) => intake.send((Source::Test, command)).await.is_ok(),
+        None => false,
+    }
+}
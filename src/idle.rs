@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::Command;
+
+/// Decides what to inject when nobody interacts: `Next` every `advance_every`
+/// as an attract mode, `GoTo(0)` once after `home_after` and `Sleep` once `sleep_after`
+/// has passed since the last real input. Playback takes a `GoTo(0)` from a timer
+/// as a new session, with no history or resume points left over.
+/// Attract mode stays off after sleeping until someone comes back.
+pub struct Idle {
+    sleep_after: Option<Duration>,
+    advance_every: Option<Duration>,
+    home_after: Option<Duration>,
+    last_input: Instant,
+    last_advance: Instant,
+    asleep: bool,
+    at_home: bool,
+}
+
+impl Idle {
+    pub fn new(
+        sleep_after: Option<Duration>,
+        advance_every: Option<Duration>,
+        home_after: Option<Duration>,
+    ) -> Self {
+        let now = Instant::now();
+        Idle {
+            sleep_after,
+            advance_every,
+            home_after,
+            last_input: now,
+            last_advance: now,
+            asleep: false,
+            at_home: false,
+        }
+    }
+
+    /// When the next timer fires and what it injects, `None` with all timers off.
+    /// The playing fragment's own timeouts, if any, stand in for the global ones.
+    pub fn next(
+        &self,
+        advance_every: Option<Duration>,
+        sleep_after: Option<Duration>,
+    ) -> Option<(Instant, Command)> {
+        let sleep = sleep_after
+            .or(self.sleep_after)
+            .map(|after| (self.last_input + after, Command::Sleep));
+        let home = self
+            .home_after
+            .filter(|_| !self.asleep && !self.at_home)
+            .map(|after| (self.last_input + after, Command::GoTo(0)));
+        let advance = advance_every
+            .or(self.advance_every)
+            .filter(|_| !self.asleep)
+            .map(|every| (self.last_advance + every, Command::Next));
+        // Sleep wins a tie, then going home
+        [sleep, home, advance]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(at, _)| *at)
+    }
+
+    pub fn input(&mut self) {
+        self.last_input = Instant::now();
+        self.last_advance = self.last_input;
+        self.asleep = false;
+        self.at_home = false;
+    }
+
+    /// Restarts the sleep timer without counting as input
+    pub fn postpone(&mut self) {
+        self.last_input = Instant::now();
+    }
+
+    pub fn fired(&mut self, command: &Command) {
+        match command {
+            Command::Sleep => {
+                // Keep sleeping again every `sleep_after`, like a plain read timeout would
+                self.last_input = Instant::now();
+                self.asleep = true;
+            }
+            Command::GoTo(_) => self.at_home = true,
+            _ => self.last_advance = Instant::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLEEP: Duration = Duration::from_secs(60);
+    const HOME: Duration = Duration::from_secs(30);
+    const ADVANCE: Duration = Duration::from_secs(10);
+
+    fn next(idle: &Idle) -> Option<Command> {
+        idle.next(None, None).map(|(_, command)| command)
+    }
+
+    #[test]
+    fn attract_mode_comes_first() {
+        let idle = Idle::new(Some(SLEEP), Some(ADVANCE), Some(HOME));
+        assert!(matches!(next(&idle), Some(Command::Next)));
+    }
+
+    #[test]
+    fn home_comes_once_before_sleep() {
+        let mut idle = Idle::new(Some(SLEEP), None, Some(HOME));
+        assert!(matches!(next(&idle), Some(Command::GoTo(0))));
+        idle.fired(&Command::GoTo(0));
+        assert!(matches!(next(&idle), Some(Command::Sleep)));
+    }
+
+    #[test]
+    fn sleep_wins_a_tie() {
+        let idle = Idle::new(Some(HOME), None, Some(HOME));
+        assert!(matches!(next(&idle), Some(Command::Sleep)));
+    }
+
+    #[test]
+    fn attract_mode_stays_off_asleep_until_input() {
+        let mut idle = Idle::new(Some(SLEEP), Some(ADVANCE), Some(HOME));
+        idle.fired(&Command::Sleep);
+        assert!(matches!(next(&idle), Some(Command::Sleep)));
+        idle.input();
+        assert!(matches!(next(&idle), Some(Command::Next)));
+    }
+
+    #[test]
+    fn fragment_timeouts_stand_in_for_the_global_ones() {
+        let idle = Idle::new(Some(SLEEP), None, None);
+        let (at, command) = idle.next(None, Some(ADVANCE)).unwrap();
+        assert!(matches!(command, Command::Sleep));
+        assert!(at <= Instant::now() + ADVANCE);
+        assert!(matches!(idle.next(Some(ADVANCE), None), Some((_, Command::Next))));
+    }
+
+    #[test]
+    fn nothing_fires_with_every_timer_off() {
+        assert!(next(&Idle::new(None, None, None)).is_none());
+    }
+}
@@ -0,0 +1,2708 @@
+#![feature(linked_list_cursors)]
+mod bundle;
+mod control;
+mod disk;
+mod error;
+mod fifo;
+#[cfg(feature = "gpio")]
+mod gpio;
+#[cfg(feature = "test-hooks")]
+pub mod hooks;
+mod idle;
+mod logging;
+mod order;
+mod persist;
+mod schedule;
+mod selftest;
+mod serial;
+mod settings;
+mod state;
+mod supervise;
+mod sync;
+mod thumbnails;
+mod transitions;
+mod verify;
+mod web;
+use anyhow::Result;
+use anyhow::anyhow;
+use bytes::BufMut;
+use bytes::BytesMut;
+use error::CavempvError;
+use libmpv::Format;
+use libmpv::events::Event;
+use libmpv::events::*;
+use libmpv::{FileState, Mpv, SetData};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use idle::Idle;
+use order::Order;
+use settings::{
+    EndStep, Fragment, PanZoom, PressMapping, PrevAction, Role, Settings, SleepAction, SyncRole,
+};
+use state::{Source, State};
+use std::time::Duration;
+use std::{
+    collections::{HashMap, LinkedList, linked_list::Cursor},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::time::{Instant, timeout, timeout_at};
+use tokio::process;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::AbortHandle;
+use tokio::sync::{
+    Mutex, broadcast,
+    mpsc::{self, error::TrySendError},
+};
+use tokio_serial::SerialStream;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "snake_case"))]
+pub enum Command {
+    Next,
+    Prev,
+    Sleep,
+    VolumeUp,
+    VolumeDown,
+    SetVolume(u8),
+    /// Overrides what gets signalled to the hardware, `None` goes back to automatic
+    ForceButtonState(Option<ButtonState>),
+    NextAudioDevice,
+    ToggleFullscreen,
+    /// From the display schedule, `false` during the off window
+    DisplayPower(bool),
+    /// Shows the hostname and blinks the buttons for a moment
+    Identify,
+    /// Jumps straight to a playlist index
+    GoTo(usize),
+    /// Back to a clean start: first fragment, initial volume, no overrides, and the playlist
+    /// and serial ports reread from the config. Not a config reload, every other setting
+    /// stays as it was started with and mpv keeps running, only `Restart` picks those up.
+    Reset,
+    /// Swaps in the content pack in this directory at the next transition
+    LoadPack(String),
+    SetSpeed(f32),
+    ResetSpeed,
+    /// A black fragment's hold ran out, carries the sequence number that started it
+    BlankOver(u64),
+    /// Keeps the inactivity timer from sleeping until released with `false`
+    HoldAwake(bool),
+    /// Swaps in one of the named playlists right away
+    SwitchPlaylist(String),
+    /// Moves this many fragments on, back when negative, wrapping around
+    Skip(i32),
+    /// Silences the audio while the picture carries on, or brings it back
+    ToggleMute,
+    /// Feeds the steps of one of the configured macros through the intake
+    RunMacro(String),
+    /// Shuts down cleanly and starts the player over
+    Restart,
+}
+
+/// A command on its way to playback, stamped to measure how long it takes to show
+struct Queued {
+    seq: u64,
+    at: Instant,
+    source: Source,
+    command: Command,
+}
+
+impl Queued {
+    fn new(source: Source, command: Command) -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        Queued {
+            seq: SEQ.fetch_add(1, Ordering::Relaxed),
+            at: Instant::now(),
+            source,
+            command,
+        }
+    }
+}
+
+const AUDIO_DEVICE: &str = "pipewire/combined";
+/// Good as forever, the message goes once something plays again
+const ERROR_OSD_HOLD: Duration = Duration::from_secs(24 * 60 * 60);
+/// Anything quieter counts as silence for `silence_ms`
+const SILENCE_THRESHOLD: &str = "-50dB";
+const SILENCE_METADATA: &str = "af-metadata/silence";
+/// Longest jump a Skip takes, anything further is a jog dial gone haywire
+const MAX_SKIP: i32 = 100;
+/// Commands waiting for playback, Next and Prev beyond that get dropped
+const PLAYBACK_QUEUE: usize = 4;
+/// Commands from all sources waiting to be looked at, serial drops beyond that
+const INTAKE_QUEUE: usize = 32;
+/// Pause between macro steps unless `macro_step_ms` says otherwise
+const MACRO_STEP_DEFAULT: Duration = Duration::from_millis(500);
+/// Longest a restart waits for the serial writers to get out what's queued
+const RESTART_GRACE: Duration = Duration::from_secs(1);
+const VOLUME_STEP: f64 = 5.0;
+/// Length of the fade forced onto every cut by `force_fade_on_cut`
+const CUT_FADE: Duration = Duration::from_millis(300);
+const FADE_STEPS: u32 = 20;
+/// How often `pan_zoom` moves the picture, slow drifts don't need more
+const PAN_ZOOM_TICK: Duration = Duration::from_millis(100);
+/// How long a failed startup keeps its notice on screen before exiting
+const STARTUP_ERROR_HOLD: Duration = Duration::from_secs(30);
+const IDENTIFY_DEFAULT: Duration = Duration::from_secs(5);
+const CAPTION_DEFAULT: Duration = Duration::from_secs(5);
+/// How far back `prev_action: history` reaches
+const VISITED_LEN: usize = 32;
+const BLANK_DEFAULT: Duration = Duration::from_secs(3);
+const SCREEN_CHECK_DELAY: Duration = Duration::from_secs(2);
+/// How long an inactivity Sleep waits for a command that would cancel it
+const SLEEP_COALESCE: Duration = Duration::from_millis(250);
+/// PipeWire and the compositor may still be coming up when we start
+const LAUNCH_RETRIES: usize = 5;
+const LAUNCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Right after a `loadfile` mpv briefly reports properties as unavailable
+const PROPERTY_RETRIES: u32 = 4;
+/// Doubled after every failed read
+const PROPERTY_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "snake_case"))]
+pub enum ButtonState {
+    StartOnly,
+    Both,
+    /// Both buttons dark, from the control interface
+    Off,
+    /// An intro that can't be skipped is playing
+    Locked,
+    /// mpv stalled waiting for the cache
+    Blinking,
+    /// Muted from the control interface
+    Muted,
+}
+
+/// Property changes we react to, detached from the event context lifetime
+#[derive(Debug)]
+enum Observed {
+    Filename(String),
+    /// A file is loaded far enough to seek in
+    Loaded,
+    Pause(bool),
+    Buffering(bool),
+    /// mpv reported an error, mostly a file that failed to load
+    Failed(String),
+    /// Latest metadata from the silence detection filter
+    Silence(String),
+    /// One of mpv's own messages, `prefix` names the mpv module it came from
+    Log {
+        prefix: String,
+        level: log::Level,
+        text: String,
+    },
+}
+
+/// mpv's message levels on ours, `None` for levels it never sends
+fn log_level(level: &str) -> Option<log::Level> {
+    match level {
+        "fatal" | "error" => Some(log::Level::Error),
+        "warn" => Some(log::Level::Warn),
+        "info" => Some(log::Level::Info),
+        "v" | "debug" => Some(log::Level::Debug),
+        "trace" => Some(log::Level::Trace),
+        _ => None,
+    }
+}
+
+fn observe(event: Event) -> Option<Observed> {
+    match event {
+        Event::PropertyChange {
+            name: "filename",
+            change: PropertyData::Str(filename),
+            reply_userdata: _,
+        } => Some(Observed::Filename(filename.to_owned())),
+        Event::PropertyChange {
+            name: "pause",
+            change: PropertyData::Flag(paused),
+            reply_userdata: _,
+        } => Some(Observed::Pause(paused)),
+        Event::PropertyChange {
+            name: SILENCE_METADATA,
+            change: PropertyData::Str(metadata),
+            reply_userdata: _,
+        } => Some(Observed::Silence(metadata.to_owned())),
+        Event::PropertyChange {
+            name: "paused-for-cache",
+            change: PropertyData::Flag(buffering),
+            reply_userdata: _,
+        } => Some(Observed::Buffering(buffering)),
+        Event::FileLoaded => Some(Observed::Loaded),
+        Event::LogMessage {
+            prefix,
+            level,
+            text,
+            log_level: _,
+        } => log_level(level).map(|level| Observed::Log {
+            prefix: prefix.to_owned(),
+            level,
+            text: text.trim_end().to_owned(),
+        }),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct LineCodec {
+    press: PressMapping,
+}
+
+impl Decoder for LineCodec {
+    type Item = Command;
+
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(n) = src.as_ref().iter().position(|b| *b == b'\n') {
+            let line = src.split_to(n + 1);
+            // Single-button hardware annotates the press instead, `n:short` or `n:long`
+            let frame = line.trim_ascii_end();
+            if frame.ends_with(b":short") {
+                return Ok(Some(self.press.short.clone()));
+            }
+            if frame.ends_with(b":long") {
+                return Ok(Some(self.press.long.clone()));
+            }
+            // The command is the last byte before the line ending, be it \n or \r\n
+            let Some(command) = line.iter().rev().find(|b| !matches!(b, b'\r' | b'\n')) else {
+                continue;
+            };
+            match command {
+                b'n' => return Ok(Some(Command::Next)),
+                b'p' => return Ok(Some(Command::Prev)),
+                b's' => return Ok(Some(Command::Sleep)),
+                b'r' => return Ok(Some(Command::Reset)),
+                unknown => warn!(
+                    "Unknown command byte {unknown:#04x} ({:?}), firmware mismatch?",
+                    char::from(*unknown)
+                ),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// One byte per state and a newline, the firmware lights the buttons to match:
+/// `s` only start, `b` both, `o` none, `l` locked intro, `w` blinking, `m` muted
+impl Encoder<ButtonState> for LineCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, _item: ButtonState, _dst: &mut BytesMut) -> Result<(), Self::Error> {
+        (*_dst).put_u8(match _item {
+            ButtonState::StartOnly => b's',
+            ButtonState::Both => b'b',
+            ButtonState::Off => b'o',
+            ButtonState::Locked => b'l',
+            ButtonState::Blinking => b'w',
+            ButtonState::Muted => b'm',
+        });
+        (*_dst).put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// Byte sent to check the firmware is listening, see `serial::boot`
+struct Probe(u8);
+
+/// What a port gets written
+#[derive(Debug, Clone)]
+enum Outbound {
+    Buttons(ButtonState),
+    /// A command from the hardware was accepted, carries `serial_ack`
+    Ack(u8),
+    /// Write out what's queued and stop, nothing goes on the wire for it
+    Shutdown,
+}
+
+impl Encoder<Outbound> for LineCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Outbound, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            Outbound::Buttons(button_state) => self.encode(button_state, dst),
+            Outbound::Ack(ack) => self.encode(Probe(ack), dst),
+            Outbound::Shutdown => Ok(()),
+        }
+    }
+}
+
+impl Encoder<Probe> for LineCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Probe, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_u8(item.0);
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+trait PlaylistAdder {
+    fn replace(&self, path: &str, inf_loop: bool) -> error::Result<()>;
+    fn queue(&self, path: &str, inf_loop: bool) -> error::Result<()>;
+    fn clear(&self) -> error::Result<()>;
+}
+
+impl PlaylistAdder for Mpv {
+    fn replace(&self, path: &str, inf_loop: bool) -> error::Result<()> {
+        self.command(
+            "loadfile",
+            &[
+                path,
+                "replace",
+                "0",
+                if inf_loop {
+                    "loop-file=inf"
+                } else {
+                    "loop-file=0"
+                },
+            ],
+        )
+        .map_err(|e| mpv_failed(format!("loadfile {path} replace"), e))
+    }
+    fn queue(&self, path: &str, inf_loop: bool) -> error::Result<()> {
+        self.command(
+            "loadfile",
+            &[
+                path,
+                "append-play",
+                "0",
+                if inf_loop {
+                    "loop-file=inf"
+                } else {
+                    "loop-file=0"
+                },
+            ],
+        )
+        .map_err(|e| mpv_failed(format!("loadfile {path} append-play"), e))
+    }
+    fn clear(&self) -> error::Result<()> {
+        self.playlist_clear()
+            .map_err(|e| mpv_failed("playlist-clear".to_string(), e))
+    }
+}
+
+/// Property writes that fail as `CavempvError::MpvCommand` instead of panicking
+trait SetProperty {
+    fn set<T: SetData>(&self, name: &str, value: T) -> error::Result<()>;
+}
+
+impl SetProperty for Mpv {
+    fn set<T: SetData>(&self, name: &str, value: T) -> error::Result<()> {
+        self.set_property(name, value)
+            .map_err(|e| mpv_failed(format!("set {name}"), e))
+    }
+}
+
+fn mpv_failed(command: String, e: libmpv::Error) -> CavempvError {
+    CavempvError::MpvCommand {
+        command,
+        reason: format!("{e:?}"),
+    }
+}
+
+/// Playback keeps going past a failed mpv call, the kiosk is better off with a glitch
+trait OrLog {
+    fn or_log(self);
+}
+
+impl OrLog for error::Result<()> {
+    fn or_log(self) {
+        if let Err(e) = self {
+            error!("{e}");
+        }
+    }
+}
+
+/// Ramps brightness down to black, the observer brings it back once the next file loads
+async fn fade_to_black(mpv: &Mpv, duration: Duration) {
+    for step in 1..=FADE_STEPS {
+        let brightness = -100 * i64::from(step) / i64::from(FADE_STEPS);
+        if let Err(e) = mpv.set_property("brightness", brightness) {
+            warn!("Failed to fade: {e:?}");
+            return;
+        }
+        tokio::time::sleep(duration / FADE_STEPS).await;
+    }
+}
+
+/// Ramps brightness up from black, the way back from `fade_to_black`
+async fn fade_in(mpv: &Mpv, duration: Duration) {
+    for step in (0..=FADE_STEPS).rev() {
+        let brightness = -100 * i64::from(step) / i64::from(FADE_STEPS);
+        if let Err(e) = mpv.set_property("brightness", brightness) {
+            warn!("Failed to fade in: {e:?}");
+            mpv.set_property("brightness", 0i64).ok();
+            return;
+        }
+        if step > 0 {
+            tokio::time::sleep(duration / FADE_STEPS).await;
+        }
+    }
+}
+
+/// Drifts the picture along `pan_zoom` until aborted
+async fn animate_pan_zoom(mpv: &Mpv, pan_zoom: PanZoom) {
+    let started = Instant::now();
+    let mut ticks = tokio::time::interval(PAN_ZOOM_TICK);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticks.tick().await;
+        let (zoom, pan_x, pan_y) = pan_zoom.at(started.elapsed().as_secs_f64());
+        let moved = mpv
+            .set_property("video-zoom", zoom)
+            .and_then(|()| mpv.set_property("video-pan-x", pan_x))
+            .and_then(|()| mpv.set_property("video-pan-y", pan_y));
+        if let Err(e) = moved {
+            warn!("Failed to pan and zoom: {e:?}");
+            return;
+        }
+    }
+}
+
+/// Handles commands that change how things play rather than what plays,
+/// returns false for anything that needs a transition
+fn adjust_player(mpv: &Mpv, s: &Settings, cmd: &Command) -> bool {
+    let current_volume = || mpv.get_property::<f64>("volume").unwrap_or(100.0);
+    match cmd {
+        Command::VolumeUp => set_volume(mpv, s, current_volume() + VOLUME_STEP),
+        Command::VolumeDown => set_volume(mpv, s, current_volume() - VOLUME_STEP),
+        Command::SetVolume(volume) => set_volume(mpv, s, f64::from(*volume)),
+        Command::SetSpeed(speed) => set_speed(mpv, s, f64::from(*speed)),
+        Command::ResetSpeed => set_speed(mpv, s, 1.0),
+        Command::NextAudioDevice => {
+            let count = mpv
+                .get_property::<i64>("audio-device-list/count")
+                .unwrap_or(0);
+            let devices: Vec<String> = (0..count)
+                .filter_map(|index| {
+                    mpv.get_property::<String>(&format!("audio-device-list/{index}/name"))
+                        .ok()
+                })
+                .collect();
+            let current = mpv
+                .get_property::<String>("audio-device")
+                .unwrap_or_default();
+            match next_audio_device(&devices, &current) {
+                Some(device) => {
+                    info!("Switching audio device from {current} to {device}");
+                    if let Err(e) = mpv.set_property("audio-device", device.as_str()) {
+                        error!("Failed to switch audio device: {e:?}");
+                    }
+                }
+                None => warn!("No audio devices to switch to"),
+            }
+        }
+        Command::ToggleFullscreen => {
+            let fullscreen = !mpv.get_property::<bool>("fullscreen").unwrap_or(false);
+            info!("Switching fullscreen {}", if fullscreen { "on" } else { "off" });
+            if let Err(e) = mpv.set_property("fullscreen", fullscreen) {
+                error!("Failed to toggle fullscreen: {e:?}");
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn set_speed(mpv: &Mpv, s: &Settings, speed: f64) {
+    let speed = s.clamp_speed(speed);
+    info!("Setting speed to {speed}");
+    if let Err(e) = mpv.set_property("speed", speed) {
+        error!("Failed to set speed: {e:?}");
+    }
+}
+
+fn set_volume(mpv: &Mpv, s: &Settings, volume: f64) {
+    let volume = s.clamp_volume(volume);
+    info!("Setting volume to {volume}");
+    if let Err(e) = mpv.set_property("volume", volume) {
+        error!("Failed to set volume to {volume}: {e:?}");
+        return;
+    }
+    if s.persist_volume && let Some(path) = &s.state_file {
+        let mut persisted = persist::Persisted::load(path);
+        persisted.volume = Some(volume);
+        persisted.save(path);
+    }
+}
+
+/// Whether a locked intro swallows `cmd`. Waking from a held frame always goes through,
+/// the intro it paused would never end otherwise.
+fn intro_blocks(cmd: &Command, intro_locked: bool, held: bool) -> bool {
+    intro_locked && !held && matches!(cmd, Command::Next | Command::Prev | Command::Skip(_))
+}
+
+/// Where `steps` from `current` lands, wrapping around both ends
+fn skip_from(current: usize, steps: i32, len: usize) -> usize {
+    let steps = steps.clamp(-MAX_SKIP, MAX_SKIP);
+    (current as i64 + i64::from(steps)).rem_euclid(len.max(1) as i64) as usize
+}
+
+/// Cursor on `index`, or on the ghost past the end for `None`
+fn cursor_at(playlist: &LinkedList<Fragment>, index: Option<usize>) -> Cursor<'_, Fragment> {
+    let mut cursor = playlist.cursor_front();
+    match index {
+        Some(index) => (0..index).for_each(|_| cursor.move_next()),
+        None => cursor.move_prev(),
+    }
+    cursor
+}
+
+/// Passes over fragments out of their active window on `today` from wherever the cursor
+/// landed, onwards or back for `backwards`. Running off the end of the tour wraps around,
+/// unless the playlist stays on its last fragment, then it turns back instead.
+/// Bounded, a playlist with nothing active plays where it ends up.
+fn skip_inactive<'a>(
+    playlist: &'a LinkedList<Fragment>,
+    mut cursor: Cursor<'a, Fragment>,
+    order: &mut Order,
+    mut backwards: bool,
+    stay_on_last: bool,
+    today: chrono::NaiveDate,
+) -> Cursor<'a, Fragment> {
+    for _ in 0..playlist.len() {
+        // Idling past the end, nothing to pass over
+        let Some(fragment) = cursor.current().filter(|fragment| !fragment.active_on(today))
+        else {
+            break;
+        };
+        let name = fragment.display_name();
+        info!("{name} is out of its active window, passing over it");
+        let at = cursor.index();
+        // At the start of the tour going back doesn't move, so go on instead
+        let before = order.before(at).filter(|before| Some(*before) != at);
+        let next = if backwards {
+            before.or_else(|| {
+                backwards = false;
+                order.after(at)
+            })
+        } else {
+            order.after(at).or_else(|| {
+                backwards = stay_on_last;
+                before.filter(|_| stay_on_last)
+            })
+        };
+        cursor = cursor_at(playlist, Some(next.unwrap_or(order.first())));
+    }
+    cursor
+}
+
+/// The device after `current` in mpv's list, wrapping around
+fn next_audio_device<'a>(devices: &'a [String], current: &str) -> Option<&'a String> {
+    let at = devices.iter().position(|device| device == current);
+    devices.get(at.map_or(0, |at| (at + 1) % devices.len()))
+}
+
+/// What a boot looks like at a glance, logged once before playback starts
+fn diagnostics(s: &Settings, mpv: &Mpv) -> Vec<(&'static str, String)> {
+    let enabled = |on: bool| if on { "on" } else { "off" }.to_string();
+    vec![
+        ("config", Settings::config_path()),
+        ("fragments", s.playlist.len().to_string()),
+        (
+            "serial",
+            match s.serial_port.paths() {
+                [] => "off".to_string(),
+                paths => format!("{} at {} baud", paths.join(", "), s.baud_rate),
+            },
+        ),
+        (
+            "mpv",
+            mpv.get_property::<String>("mpv-version")
+                .unwrap_or_else(|e| format!("unknown ({e:?})")),
+        ),
+        (
+            "audio device",
+            mpv.get_property::<String>("audio-device")
+                .unwrap_or_else(|e| format!("unknown ({e:?})")),
+        ),
+        ("rotation", format!("{}°", s.rotation_deg.unwrap_or(0))),
+        ("control socket", s.control_socket.clone().unwrap_or_else(|| "off".to_string())),
+        ("control tcp", s.control_tcp.clone().unwrap_or_else(|| "off".to_string())),
+        ("command fifo", s.command_fifo.clone().unwrap_or_else(|| "off".to_string())),
+        (
+            "sync group",
+            s.sync_group.as_ref().map_or_else(
+                || "off".to_string(),
+                |group| format!("{} as {:?}", group.name, group.role),
+            ),
+        ),
+        ("schedule", enabled(s.display_schedule.is_some())),
+        ("gpio", enabled(cfg!(feature = "gpio"))),
+    ]
+}
+
+/// What the playing file is made of, as `mediainfo` reports it
+#[derive(Debug, Serialize)]
+struct MediaInfo {
+    path: Option<String>,
+    width: Option<i64>,
+    height: Option<i64>,
+    fps: Option<f64>,
+    video_codec: Option<String>,
+    audio_codec: Option<String>,
+}
+
+/// Fields mpv doesn't know yet, or that the file lacks, stay empty
+fn media_info(mpv: &Mpv) -> MediaInfo {
+    MediaInfo {
+        path: mpv.get_property("path").ok(),
+        width: mpv.get_property("video-params/w").ok(),
+        height: mpv.get_property("video-params/h").ok(),
+        fps: mpv.get_property("container-fps").ok(),
+        video_codec: mpv.get_property("video-codec").ok(),
+        audio_codec: mpv.get_property("audio-codec").ok(),
+    }
+}
+
+/// `display-names` lists every output the window touches, comma separated
+fn on_display(display_names: &str, expected: &str) -> bool {
+    display_names.split(',').any(|name| name.trim() == expected)
+}
+
+/// `restore` undoes a profile applied earlier
+fn apply_profile(mpv: &Mpv, profile: &str, restore: bool) {
+    info!("{} mpv profile {profile}", if restore { "Restoring" } else { "Applying" });
+    let args: &[&str] = if restore { &[profile, "restore"] } else { &[profile] };
+    if let Err(e) = mpv.command("apply-profile", args) {
+        error!("Failed to apply mpv profile {profile}: {e:?}");
+    }
+}
+
+/// Playback position within `fragment`'s static, `None` while another file plays
+fn static_position(mpv: &Mpv, fragment: &Fragment) -> Option<f64> {
+    let on_static = mpv
+        .get_property::<String>("path")
+        .is_ok_and(|path| path == fragment.static_);
+    on_static
+        .then(|| mpv.get_property::<f64>("playback-time").ok())
+        .flatten()
+}
+
+/// Sets a launch option, retrying for a while and then leaving mpv's default in place
+async fn set_launch_option<T: SetData + Copy>(mpv: &Mpv, name: &str, value: T) {
+    for attempt in 1..=LAUNCH_RETRIES {
+        match mpv.set_property(name, value) {
+            Ok(()) => return,
+            Err(e) => warn!("Failed to set {name} (attempt {attempt}/{LAUNCH_RETRIES}): {e:?}"),
+        }
+        tokio::time::sleep(LAUNCH_RETRY_DELAY).await;
+    }
+    error!("Giving up on {name}, carrying on with mpv's default");
+}
+
+/// Reads and parses a property, backing off between attempts and settling
+/// for `default` when mpv doesn't come up with a usable value
+async fn read_property<T: FromStr>(mpv: &Mpv, name: &str, default: T) -> T {
+    let mut delay = PROPERTY_RETRY_DELAY;
+    for attempt in 1..=PROPERTY_RETRIES {
+        match mpv.get_property::<String>(name) {
+            Ok(value) => match value.trim().parse() {
+                Ok(parsed) => return parsed,
+                Err(_) => warn!("Unexpected {name} {value:?}"),
+            },
+            Err(e) => warn!("Failed to read {name} (attempt {attempt}/{PROPERTY_RETRIES}): {e:?}"),
+        }
+        if attempt < PROPERTY_RETRIES {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    default
+}
+
+/// Loads every fragment into mpv's own playlist, returning the entry each one starts at
+fn load_continuous(mpv: &Mpv, playlist: &[Fragment]) -> Vec<usize> {
+    mpv.set("loop-playlist", "inf").or_log();
+    let mut starts = Vec::with_capacity(playlist.len());
+    let mut entries = 0;
+    for fragment in playlist {
+        starts.push(entries);
+        for path in fragment.intro.iter().chain([&fragment.static_]) {
+            if entries == 0 {
+                mpv.replace(path, false).or_log();
+            } else {
+                mpv.queue(path, false).or_log();
+            }
+            entries += 1;
+        }
+    }
+    starts
+}
+
+/// Playback for `continuous`, commands only move mpv along its own playlist.
+/// Returns `true` once a restart is asked for, `false` when the queues close.
+async fn step_continuous(
+    mpv: &Mpv,
+    s: &Settings,
+    starts: &[usize],
+    acks: &broadcast::Sender<(usize, u8)>,
+    mut rx: mpsc::Receiver<Queued>,
+    mut urgent_rx: mpsc::Receiver<Queued>,
+    state: &Mutex<State>,
+) -> bool {
+    loop {
+        let queued = tokio::select! {
+            biased;
+            Some(queued) = urgent_rx.recv() => queued,
+            Some(queued) = rx.recv() => queued,
+            else => break,
+        };
+        let cmd = queued.command;
+        if s.manual_only && matches!(queued.source, Source::Timer) {
+            info!("Manual only, dropping {cmd:?} from a timer");
+            continue;
+        }
+        state.lock().await.record(queued.source, &cmd);
+        if let Command::Restart = cmd {
+            return true;
+        }
+        if adjust_player(mpv, s, &cmd) {
+            continue;
+        }
+        let stepped = match cmd {
+            Command::Next => mpv.command("playlist-next", &["force"]),
+            Command::Prev => mpv.command("playlist-prev", &["force"]),
+            Command::Sleep | Command::Reset => mpv.command("playlist-play-index", &["0"]),
+            Command::GoTo(index) => match starts.get(index) {
+                Some(entry) => mpv.command("playlist-play-index", &[entry.to_string().as_str()]),
+                None => {
+                    warn!("No fragment {index}, the playlist has {}", starts.len());
+                    continue;
+                }
+            },
+            cmd => {
+                info!("{cmd:?} does nothing in continuous mode");
+                continue;
+            }
+        };
+        match stepped {
+            Ok(()) => {
+                if let Source::Serial(port) = queued.source
+                    && let Some(ack) = s.serial_ack
+                {
+                    acks.send((port, ack as u8)).ok();
+                }
+            }
+            Err(e) => error!("Failed to move along the playlist: {e:?}"),
+        }
+    }
+    false
+}
+
+/// Pins the audio track with this id or language, `None` leaves the pick to mpv.
+/// A track the file doesn't have only gets a warning.
+fn select_audio_track(mpv: &Mpv, wanted: Option<&str>) {
+    let Some(wanted) = wanted else {
+        mpv.set_property("aid", "auto").ok();
+        return;
+    };
+    let count = mpv.get_property::<i64>("track-list/count").unwrap_or(0);
+    let found = (0..count).find_map(|i| {
+        let track = |field: &str| {
+            let name = format!("track-list/{i}/{field}");
+            mpv.get_property::<String>(&name).ok()
+        };
+        let id = track("id");
+        let matches = id.as_deref() == Some(wanted) || track("lang").as_deref() == Some(wanted);
+        id.filter(|_| matches && track("type").as_deref() == Some("audio"))
+    });
+    match found {
+        Some(id) => {
+            info!("Playing audio track {id}");
+            if let Err(e) = mpv.set_property("aid", id.as_str()) {
+                warn!("Failed to pick audio track {id}: {e:?}");
+            }
+        }
+        None => {
+            warn!("No audio track {wanted}, leaving the pick to mpv");
+            mpv.set_property("aid", "auto").ok();
+        }
+    }
+}
+
+/// Stops mpv and waits for the ports to flush before starting over,
+/// the exit hooks put the relays back
+async fn restart(mpv: &Mpv, s: &Settings, closing: &broadcast::Sender<serial::Flushed>) -> ! {
+    info!("Restarting on request");
+    if let Err(e) = mpv.command("quit", &[]) {
+        warn!("Failed to quit mpv: {e:?}");
+    }
+    let (flushed, mut all_flushed) = mpsc::channel(1);
+    // Without any port up nobody gets it, and there's nothing to wait for
+    if closing.send(flushed).is_ok()
+        && timeout(RESTART_GRACE, all_flushed.recv()).await.is_err()
+    {
+        warn!("The ports didn't flush within {RESTART_GRACE:?}, restarting anyway");
+    }
+    supervise::restart(s.restart_mode)
+}
+
+/// Runs an operator supplied shell command, logging instead of failing
+async fn run_hook(cmd: &str) {
+    match process::Command::new("sh").args(["-c", cmd]).status().await {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("`{cmd}` exited with {status}"),
+        Err(e) => error!("Failed to run `{cmd}`: {e:?}"),
+    }
+}
+
+/// Paths and baud rate the ports were opened with, and the tasks serving them
+type SerialTasks = (Vec<String>, usize, Vec<AbortHandle>);
+
+/// One supervised task per port, a port opened already only serves the first run
+fn spawn_serial(
+    ports: Vec<(String, Option<SerialStream>)>,
+    s: &Settings,
+    intake: &mpsc::Sender<(Source, Command)>,
+    signalled: &broadcast::Sender<ButtonState>,
+    acks: &broadcast::Sender<(usize, u8)>,
+    closing: &broadcast::Sender<serial::Flushed>,
+    state: &Arc<Mutex<State>>,
+) -> Vec<AbortHandle> {
+    ports
+        .into_iter()
+        .enumerate()
+        .map(|(index, (path, mut port))| {
+            let (baud_rate, press) = (s.baud_rate as u32, s.press_mapping.clone());
+            let (intake, signalled, acks) = (intake.clone(), signalled.clone(), acks.clone());
+            let (closing, state) = (closing.clone(), state.clone());
+            // A restart opens the port again
+            supervise::spawn("serial", s.on_task_panic, move || {
+                serial::run(
+                    path.clone(),
+                    index,
+                    baud_rate,
+                    press.clone(),
+                    port.take(),
+                    serial::Links {
+                        intake: intake.clone(),
+                        buttons: signalled.subscribe(),
+                        acks: acks.subscribe(),
+                        closing: closing.subscribe(),
+                    },
+                    state.clone(),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Rereads the config on SIGHUP or a reset, reopening the ports only when their paths or
+/// baud rate changed
+async fn reload_on_hangup(
+    serial: Arc<Mutex<SerialTasks>>,
+    reload: Arc<tokio::sync::Notify>,
+    intake: mpsc::WeakSender<(Source, Command)>,
+    signalled_tx: broadcast::Sender<ButtonState>,
+    acks_tx: broadcast::Sender<(usize, u8)>,
+    closing_tx: broadcast::Sender<serial::Flushed>,
+    state: Arc<Mutex<State>>,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Can't reload on SIGHUP: {e:?}");
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            hangup = hangups.recv() => {
+                if hangup.is_none() {
+                    return;
+                }
+            }
+            () = reload.notified() => {}
+        }
+        let reloaded = match Settings::new() {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                error!("Failed to reload the config, keeping the serial ports: {e:?}");
+                continue;
+            }
+        };
+        let mut serial = serial.lock().await;
+        let (paths, baud_rate, tasks) = &mut *serial;
+        if reloaded.serial_port.paths() == paths.as_slice() && reloaded.baud_rate == *baud_rate {
+            info!("Reloaded the config, the serial ports stay as they are");
+            continue;
+        }
+        let Some(intake) = intake.upgrade() else {
+            return;
+        };
+        info!("Serial config changed, reopening the ports");
+        tasks.iter().for_each(AbortHandle::abort);
+        state.lock().await.serial_up.clear();
+        *paths = reloaded.serial_port.paths().to_vec();
+        *baud_rate = reloaded.baud_rate;
+        let ports = paths.iter().map(|path| (path.clone(), None)).collect();
+        *tasks = spawn_serial(
+            ports,
+            &reloaded,
+            &intake,
+            &signalled_tx,
+            &acks_tx,
+            &closing_tx,
+            &state,
+        );
+    }
+}
+
+/// Passes button states on to the ports, dropping repeats of what was last sent.
+/// A change goes out right away, anything after it within `debounce` waits that long
+/// and only the latest of those gets sent.
+async fn coalesce_buttons(
+    mut states: broadcast::Receiver<ButtonState>,
+    signalled: broadcast::Sender<ButtonState>,
+    debounce: Duration,
+) {
+    let mut last_sent = None;
+    let mut pending = None;
+    let mut quiet_until = Instant::now();
+    loop {
+        let state = tokio::select! {
+            received = states.recv() => match received {
+                Ok(state) if pending.is_none() && Instant::now() >= quiet_until => state,
+                Ok(state) => {
+                    pending = Some(state);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Coalescing fell behind by {skipped} button states");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = tokio::time::sleep_until(quiet_until), if pending.is_some() => {
+                pending.take().expect("only waits with a state pending")
+            }
+        };
+        if last_sent.as_ref() == Some(&state) {
+            continue;
+        }
+        signalled.send(state.clone()).ok();
+        last_sent = Some(state);
+        quiet_until = Instant::now() + debounce;
+    }
+}
+
+/// Everything the binary does, kept in the library so integration tests can link against it
+pub async fn run() -> Result<()> {
+    let s = Arc::new(Settings::new()?);
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--dump-config") {
+        println!("{}", serde_json::to_string_pretty(&s.masked())?);
+        return Ok(());
+    }
+
+    logging::init(&s)?;
+
+    if let Some(dir) = args
+        .iter()
+        .position(|arg| arg == "--thumbnails")
+        .and_then(|at| args.get(at + 1))
+    {
+        for thumbnail in thumbnails::generate(&s.playlist, std::path::Path::new(dir))? {
+            println!("{}", thumbnail.display());
+        }
+        return Ok(());
+    }
+
+    if s.verify_media {
+        let files = s.media_files();
+        // Hashing gigabytes of video takes a while, keep it off the runtime
+        let mismatched = tokio::task::spawn_blocking(move || verify::mismatched(&files)).await?;
+        if !mismatched.is_empty() && s.strict_config {
+            return Err(CavempvError::MediaCorrupt(mismatched.len()).into());
+        }
+    }
+
+    let mpv = match &s.mpv_config_dir {
+        // Lets mpv find the profiles in its mpv.conf
+        Some(dir) => Mpv::with_initializer(|init| {
+            init.set_property("config-dir", dir.as_str())?;
+            init.set_property("config", "yes")?;
+            Ok(())
+        }),
+        None => Mpv::new(),
+    };
+    let mpv = Arc::new(mpv.map_err(|e| CavempvError::MpvInit(format!("{e:?}")))?);
+
+    if let Some(profile) = &s.mpv_profile {
+        apply_profile(&mpv, profile, false);
+    }
+
+    // mpv refuses anything past its volume-max, 130 unless raised
+    if let Some(max_volume) = s.max_volume {
+        let volume_max = f64::from(max_volume.max(100));
+        if let Err(e) = mpv.set_property("volume-max", volume_max) {
+            warn!("Failed to set volume-max to {volume_max}, volume stays under 130: {e:?}");
+        }
+    }
+
+    if let Some(screen) = s.fs_screen {
+        info!("Pinning fullscreen to screen {screen}");
+        mpv.set("screen", screen)?;
+        mpv.set("fs-screen", screen)?;
+    }
+
+    if let Some(splash) = &s.splash {
+        info!("Showing splash {splash} while starting up");
+        mpv.replace(splash, true).or_log();
+    } else if s.startup_delay_ms.is_some() {
+        mpv.replace(settings::BLANK_SOURCE, true).or_log();
+    }
+    // Counted from mpv coming up, the rest of startup eats into it
+    let display_ready = s
+        .startup_delay_ms
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+
+    if s.serial_port.paths().is_empty() {
+        info!("No serial port configured, relying on the other command sources");
+    }
+    let mut ports = Vec::new();
+    for path in s.serial_port.paths() {
+        match serial::boot(path, &s).await {
+            Ok(port) => ports.push((path.clone(), Some(port))),
+            // Reopened in the background like any port that went away
+            Err(e) if !s.require_serial => {
+                error!("[{path}] Serial port is unusable for now, retrying: {e:?}");
+                ports.push((path.clone(), None));
+            }
+            Err(e) => {
+                error!("[{path}] Serial port is unusable: {e:?}");
+                if s.splash.is_some() {
+                    info!("Keeping the splash with an error notice for {STARTUP_ERROR_HOLD:?}");
+                    mpv.command(
+                        "show-text",
+                        &[
+                            format!("Startup failed: {e}").as_str(),
+                            STARTUP_ERROR_HOLD.as_millis().to_string().as_str(),
+                        ],
+                    )
+                    .ok();
+                    tokio::time::sleep(STARTUP_ERROR_HOLD).await;
+                }
+                return Err(e.into());
+            }
+        }
+    }
+
+    // Without serial ports nobody subscribes and button states go nowhere
+    let (buttons_tx, _) = broadcast::channel(8);
+    // What the ports actually get, after coalescing
+    let (signalled_tx, _) = broadcast::channel(8);
+    let debounce = Duration::from_millis(s.buttonstate_debounce_ms.unwrap_or(0));
+    {
+        let (buttons, signalled) = (buttons_tx.clone(), signalled_tx.clone());
+        supervise::spawn("coalescing", s.on_task_panic, move || {
+            coalesce_buttons(buttons.subscribe(), signalled.clone(), debounce)
+        });
+    }
+    let (acks_tx, _) = broadcast::channel(8);
+    // Only ever carries the one restart
+    let (closing_tx, _) = broadcast::channel(1);
+
+    let (tx, mut rx) = mpsc::channel(PLAYBACK_QUEUE);
+    // Sleep gets its own lane so it never waits behind a flood of transitions
+    let (urgent_tx, mut urgent_rx) = mpsc::channel(1);
+    let (intake_tx, mut intake_rx) = mpsc::channel::<(Source, Command)>(INTAKE_QUEUE);
+
+    let mpv_arc = mpv.clone();
+
+    let state = Arc::new(Mutex::new(State {
+        playlist: Arc::new(s.playlist.clone()),
+        ..State::default()
+    }));
+    let observer_state = state.clone();
+    let playback_state = state.clone();
+    let base_profile = s.mpv_profile.clone();
+    let mpv_log_level = s.mpv_log_level.clone();
+    let transition_clip = s.transition_clip.clone();
+    let continuous = s.continuous;
+    let buttons = buttons_tx.clone();
+    let silence_tx = tx.clone();
+    let manual_only = s.manual_only;
+    let error_osd = s.error_osd.clone();
+    let watermark = s.watermark.as_ref().map(settings::Watermark::filter);
+
+    supervise::spawn("observer", s.on_task_panic, move || {
+        let mut mpv_arc = mpv_arc.clone();
+        let observer_state = observer_state.clone();
+        let base_profile = base_profile.clone();
+        let mpv_log_level = mpv_log_level.clone();
+        let transition_clip = transition_clip.clone();
+        let buttons = buttons.clone();
+        let silence_tx = silence_tx.clone();
+        let error_osd = error_osd.clone();
+        let watermark = watermark.clone();
+        async move {
+            let mut ev_ctx = mpv_arc.create_event_context();
+
+            ev_ctx
+                .disable_deprecated_events()
+                .expect("to disable depreciated");
+
+            ev_ctx
+                .observe_property("filename", Format::String, 0)
+                .expect("to subscribe to file change event");
+
+            ev_ctx
+                .observe_property("pause", Format::Flag, 0)
+                .expect("to subscribe to pause event");
+
+            ev_ctx
+                .observe_property("paused-for-cache", Format::Flag, 0)
+                .expect("to subscribe to buffering event");
+
+            ev_ctx
+                .observe_property(SILENCE_METADATA, Format::String, 0)
+                .expect("to subscribe to silence detection");
+
+            if let Some(level) = &mpv_log_level
+                && let Err(e) = ev_ctx.request_log_messages(level)
+            {
+                error!("Failed to request mpv messages at {level}: {e:?}");
+            }
+
+            // Profile of the fragment playing, undone once it's left
+            let mut fragment_profile: Option<String> = None;
+            // Fragment whose intro or static played last, a new one brings its caption
+            let mut started: Option<usize> = None;
+            // Fade-in still ramping, the next file cuts it short
+            let mut fading_in: Option<tokio::task::JoinHandle<()>> = None;
+            // Static being panned and zoomed, by playlist index
+            let mut panning: Option<(usize, tokio::task::JoinHandle<()>)> = None;
+            // The static playing advances on silence and hasn't yet
+            let mut silence_armed = false;
+            // OSD options the error message changed, put back once something plays
+            let mut error_shown: Option<Vec<(&str, String)>> = None;
+
+            loop {
+                let observed = match ev_ctx.wait_event(60.) {
+                    Some(Ok(event)) => observe(event),
+                    Some(Err(e)) => Some(Observed::Failed(format!("{e:?}"))),
+                    None => None,
+                };
+                match observed {
+                    Some(Observed::Filename(filename)) => {
+                        info!("Filename changed: {filename}");
+                        debug!("Playing {:?}", media_info(&mpv_arc));
+                        if let Some(fade) = fading_in.take() {
+                            fade.abort();
+                        }
+                        if let Some(restore) = error_shown.take() {
+                            mpv_arc.command("show-text", &["", "1"]).ok();
+                            for (name, value) in restore {
+                                mpv_arc.set_property(name, value.as_str()).ok();
+                            }
+                        }
+                        // Undo any fade to black that preceded the cut
+                        mpv_arc.set("brightness", 0i64).or_log();
+                        if let Some((seq, at)) = observer_state.lock().await.awaiting.take() {
+                            let latency = at.elapsed();
+                            info!("Command #{seq} took {latency:?} to show");
+                            observer_state.lock().await.latency_ms = Some(latency.as_millis());
+                        }
+                        let (current, fragments) = {
+                            let state = observer_state.lock().await;
+                            (state.current, state.playlist.clone())
+                        };
+                        let located = settings::locate(&fragments, current, &filename);
+                        let intro_locked = located.is_some_and(|(index, role)| {
+                            role == Role::Intro && fragments[index].lock_during_intro == Some(true)
+                        });
+                        {
+                            let mut state = observer_state.lock().await;
+                            state.intro_locked = intro_locked;
+                            // Nobody else follows mpv through its own playlist
+                            if continuous && let Some((index, _)) = located {
+                                state.current = index;
+                            }
+                        }
+
+                        let profile =
+                            located.and_then(|(index, _)| fragments[index].mpv_profile.clone());
+                        if profile != fragment_profile {
+                            if let Some(left) = fragment_profile.take() {
+                                apply_profile(&mpv_arc, &left, true);
+                                if let Some(base) = &base_profile {
+                                    apply_profile(&mpv_arc, base, false);
+                                }
+                            }
+                            if let Some(profile) = &profile {
+                                apply_profile(&mpv_arc, profile, false);
+                            }
+                            fragment_profile = profile;
+                        }
+                        if let Some((index, Role::Intro | Role::Static)) = located
+                            && started != Some(index)
+                        {
+                            started = Some(index);
+                            if let Some(fade_in_ms) = fragments[index].fade_in_ms {
+                                let duration = Duration::from_millis(fade_in_ms.into());
+                                info!("Fading in over {duration:?}");
+                                let mpv = mpv_arc.clone();
+                                fading_in = Some(tokio::spawn(async move {
+                                    fade_in(&mpv, duration).await;
+                                }));
+                            }
+                            // OSD font and position come from mpv's own osd options
+                            let (caption, duration) = match &fragments[index].caption {
+                                Some(caption) => {
+                                    let duration = fragments[index]
+                                        .caption_duration_sec
+                                        .map_or(CAPTION_DEFAULT, Duration::from_secs_f32);
+                                    info!("Showing caption {caption:?} for {duration:?}");
+                                    (caption.as_str(), duration)
+                                }
+                                // Clears whatever the previous fragment left up
+                                None => ("", Duration::from_millis(1)),
+                            };
+                            mpv_arc
+                                .command(
+                                    "show-text",
+                                    &[caption, duration.as_millis().to_string().as_str()],
+                                )
+                                .ok();
+                        }
+
+                        let pan_zoom = located
+                            .filter(|(_, role)| *role == Role::Static)
+                            .and_then(|(index, _)| {
+                                fragments[index].pan_zoom.clone().map(|pan_zoom| (index, pan_zoom))
+                            });
+                        if panning.as_ref().map(|(index, _)| *index)
+                            != pan_zoom.as_ref().map(|(index, _)| *index)
+                        {
+                            if let Some((_, animation)) = panning.take() {
+                                animation.abort();
+                                mpv_arc.set_property("video-zoom", 0.0).ok();
+                                mpv_arc.set_property("video-pan-x", 0.0).ok();
+                                mpv_arc.set_property("video-pan-y", 0.0).ok();
+                            }
+                            if let Some((index, pan_zoom)) = pan_zoom {
+                                let mpv = mpv_arc.clone();
+                                panning = Some((
+                                    index,
+                                    tokio::spawn(async move {
+                                        animate_pan_zoom(&mpv, pan_zoom).await;
+                                    }),
+                                ));
+                            }
+                        }
+
+                        // The hardware keeps what it showed through the transition clip
+                        let transition = transition_clip
+                            .as_deref()
+                            .is_some_and(|clip| settings::is_file(clip, &filename));
+                        let button_state = match located {
+                            _ if transition => None,
+                            _ if intro_locked => Some(ButtonState::Locked),
+                            Some((index, Role::Intro | Role::Static))
+                                if fragments[index].button_state.is_some() =>
+                            {
+                                fragments[index].button_state.clone()
+                            }
+                            Some((_, Role::Static)) => Some(ButtonState::StartOnly),
+                            Some((_, Role::Intro | Role::Fadeout)) => Some(ButtonState::Both),
+                            None => {
+                                warn!("{filename} is not part of any fragment, assuming it does not loop");
+                                Some(ButtonState::Both)
+                            }
+                        };
+                        if let Some(button_state) = button_state {
+                            let button_state = observer_state.lock().await.signal(button_state);
+                            buttons.send(button_state).ok();
+                        }
+
+                        match located
+                            .filter(|(_, role)| *role == Role::Static)
+                            .and_then(|(index, _)| fragments[index].ab_loop())
+                        {
+                            Some((start, end)) => {
+                                info!("Looping {filename} between {start}s and {end}s");
+                                mpv_arc.set("ab-loop-a", start).or_log();
+                                mpv_arc.set("ab-loop-b", end).or_log();
+                            }
+                            None => {
+                                mpv_arc.set("ab-loop-a", "no").or_log();
+                                mpv_arc.set("ab-loop-b", "no").or_log();
+                            }
+                        }
+
+                        // Put on again with every file, a loadfile may have dropped it
+                        if let Some(filter) = &watermark {
+                            mpv_arc.command("vf", &["remove", "@watermark"]).ok();
+                            if let Err(e) = mpv_arc.command("vf", &["add", filter.as_str()]) {
+                                error!("Failed to overlay the watermark: {e:?}");
+                            }
+                        }
+
+                        // Nothing to remove is fine, the filter only stays on for its own static
+                        mpv_arc.command("af", &["remove", "@silence"]).ok();
+                        silence_armed = false;
+                        if let Some(silence_ms) = located
+                            .filter(|(_, role)| !manual_only && *role == Role::Static)
+                            .and_then(|(index, _)| fragments[index].silence_ms)
+                        {
+                            let filter = format!(
+                                "@silence:lavfi=[silencedetect=n={SILENCE_THRESHOLD}:d={}]",
+                                silence_ms as f64 / 1000.0
+                            );
+                            match mpv_arc.command("af", &["add", filter.as_str()]) {
+                                Ok(()) => silence_armed = true,
+                                Err(e) => error!("Failed to start silence detection: {e:?}"),
+                            }
+                        }
+                    }
+                    Some(Observed::Failed(e)) => {
+                        error!("Playback failed: {e}");
+                        if let Some(osd) = &error_osd
+                            && error_shown.is_none()
+                        {
+                            let mut restore = Vec::new();
+                            let styling = [
+                                ("osd-font-size", osd.font_size.map(|size| size.to_string())),
+                                ("osd-color", osd.color.clone()),
+                            ];
+                            for (name, value) in styling {
+                                if let Some(value) = value {
+                                    let previous = mpv_arc.get_property::<String>(name);
+                                    restore.push((name, previous.unwrap_or_default()));
+                                    mpv_arc.set_property(name, value.as_str()).ok();
+                                }
+                            }
+                            let hold = ERROR_OSD_HOLD.as_millis().to_string();
+                            mpv_arc.command("show-text", &[osd.text.as_str(), hold.as_str()]).ok();
+                            error_shown = Some(restore);
+                        }
+                    }
+                    Some(Observed::Silence(metadata)) => {
+                        if silence_armed
+                            && metadata.contains("silence_start")
+                            && !metadata.contains("silence_end")
+                        {
+                            info!("Gone silent, moving on");
+                            silence_armed = false;
+                            // Waiting on playback here would hold up every other event
+                            if silence_tx
+                                .try_send(Queued::new(Source::Timer, Command::Next))
+                                .is_err()
+                            {
+                                warn!("Playback is busy, not moving on after all");
+                            }
+                        }
+                    }
+                    Some(Observed::Loaded) => {
+                        // Track lists are only there once the file is loaded
+                        let filename =
+                            mpv_arc.get_property::<String>("filename").unwrap_or_default();
+                        let (current, fragments) = {
+                            let state = observer_state.lock().await;
+                            (state.current, state.playlist.clone())
+                        };
+                        let located = settings::locate(&fragments, current, &filename);
+
+                        // mpv drops external tracks on every file change, so nothing bleeds over,
+                        // and sub-add needs the file loaded to attach to
+                        if let Some(sub_file) = located
+                            .filter(|(_, role)| *role == Role::Static)
+                            .and_then(|(index, _)| fragments[index].sub_file.as_deref())
+                        {
+                            info!("Adding subtitles {sub_file}");
+                            if let Err(e) = mpv_arc.command("sub-add", &[sub_file, "select"]) {
+                                error!("Failed to add subtitles {sub_file}: {e:?}");
+                            }
+                            mpv_arc.set("sub-visibility", true).or_log();
+                        }
+
+                        // A pinned track sticks across files, everything else goes back to auto
+                        select_audio_track(
+                            &mpv_arc,
+                            located
+                                .filter(|(_, role)| matches!(role, Role::Intro | Role::Static))
+                                .and_then(|(index, _)| fragments[index].audio_track.as_deref()),
+                        );
+
+                        let path = mpv_arc.get_property::<String>("path").unwrap_or_default();
+                        let seek = observer_state
+                            .lock()
+                            .await
+                            .seek_on_load
+                            .take_if(|(file, _)| *file == path);
+                        if let Some((_, position)) = seek {
+                            info!("Picking {path} up at {position:.1}s");
+                            let position = position.to_string();
+                            if let Err(e) = mpv_arc.command("seek", &[&position, "absolute"]) {
+                                warn!("Failed to seek to where {path} was left: {e:?}");
+                            }
+                        }
+                    }
+                    Some(Observed::Pause(paused)) => {
+                        info!("Pause changed: {paused}");
+                        observer_state.lock().await.paused = paused;
+                    }
+                    Some(Observed::Buffering(buffering)) => {
+                        let mut state = observer_state.lock().await;
+                        state.buffering = buffering;
+                        let button_state = if buffering {
+                            warn!("Stalled waiting for the cache");
+                            Some(ButtonState::Blinking)
+                        } else {
+                            info!("Buffering finished");
+                            state.shown()
+                        };
+                        if let Some(button_state) = button_state {
+                            buttons.send(button_state).ok();
+                        }
+                    }
+                    Some(Observed::Log {
+                        prefix,
+                        level,
+                        text,
+                    }) => log::log!(target: "mpv", level, "[{prefix}] {text}"),
+                    None => {}
+                }
+            }
+        }
+    });
+
+    let sleep_timeout = Duration::from_secs(s.sleep_timeout_sec.try_into().unwrap());
+    let attract_interval = s
+        .attract_advance_sec
+        .map(|sec| Duration::from_secs(sec.try_into().unwrap()));
+    // A running programme mustn't get thrown back to its first file by the idle timers
+    let idle_off = s.manual_only || s.continuous;
+    let mut idle = if s.manual_only {
+        info!("Manual only, nothing will happen without a command");
+        Idle::new(None, None, None)
+    } else if s.continuous {
+        info!("Continuous mode, no inactivity timers");
+        Idle::new(None, None, None)
+    } else {
+        let home_after = s
+            .reset_to_home_sec
+            .map(|sec| Duration::from_secs(sec.try_into().unwrap()));
+        Idle::new(Some(sleep_timeout), attract_interval, home_after)
+    };
+
+    let control = control::Control {
+        intake: intake_tx.clone(),
+        state: state.clone(),
+        token: s.control_token.clone(),
+        settings: s.clone(),
+        mpv: mpv.clone(),
+        last_screenshot: Arc::new(Mutex::new(None)),
+    };
+
+    if let Some(path) = s.control_socket.clone() {
+        let control = control.clone();
+        let authenticate = !s.control_socket_exempt;
+        supervise::spawn("control socket", s.on_task_panic, move || {
+            let (control, path) = (control.clone(), path.clone());
+            async move {
+                if let Err(e) = control.serve_unix(path, authenticate).await {
+                    error!("Control socket failed: {e:?}");
+                }
+            }
+        });
+    }
+
+    if let Some(addr) = s.web_ui.clone() {
+        let control = control.clone();
+        supervise::spawn("web UI", s.on_task_panic, move || {
+            let (control, addr) = (control.clone(), addr.clone());
+            async move {
+                if let Err(e) = web::serve(control, addr).await {
+                    error!("Web UI failed: {e:?}");
+                }
+            }
+        });
+    }
+
+    if let Some(min_free_mb) = s.min_free_mb {
+        let (settings, state) = (s.clone(), state.clone());
+        supervise::spawn("disk watch", s.on_task_panic, move || {
+            disk::watch(settings.clone(), min_free_mb, state.clone())
+        });
+    }
+
+    if let Some(path) = s.command_fifo.clone() {
+        let intake = intake_tx.clone();
+        supervise::spawn("command fifo", s.on_task_panic, move || {
+            fifo::run(path.clone(), intake.clone())
+        });
+    }
+
+    if let Some(addr) = s.control_tcp.clone() {
+        supervise::spawn("control TCP", s.on_task_panic, move || {
+            let (control, addr) = (control.clone(), addr.clone());
+            async move {
+                if let Err(e) = control.serve_tcp(addr).await {
+                    error!("Control TCP listener failed: {e:?}");
+                }
+            }
+        });
+    }
+
+    let serial_tasks = spawn_serial(
+        ports,
+        &s,
+        &intake_tx,
+        &signalled_tx,
+        &acks_tx,
+        &closing_tx,
+        &state,
+    );
+    // Kept outside the reload task so a restart of it still knows what's open
+    let serial: Arc<Mutex<SerialTasks>> =
+        Arc::new(Mutex::new((s.serial_port.paths().to_vec(), s.baud_rate, serial_tasks)));
+    // Reset rereads the config the same way SIGHUP does
+    let reload = Arc::new(tokio::sync::Notify::new());
+    {
+        let (serial, reload) = (serial.clone(), reload.clone());
+        // Weak, so reloading alone doesn't keep the intake open
+        let intake = intake_tx.downgrade();
+        let (signalled_tx, acks_tx) = (signalled_tx.clone(), acks_tx.clone());
+        let (closing_tx, state) = (closing_tx.clone(), state.clone());
+        supervise::spawn("config reload", s.on_task_panic, move || {
+            let (serial, reload, intake) = (serial.clone(), reload.clone(), intake.clone());
+            let (signalled_tx, acks_tx) = (signalled_tx.clone(), acks_tx.clone());
+            let (closing_tx, state) = (closing_tx.clone(), state.clone());
+            reload_on_hangup(serial, reload, intake, signalled_tx, acks_tx, closing_tx, state)
+        });
+    }
+    let leader = match &s.sync_group {
+        Some(group) if group.role == SyncRole::Leader => Some(sync::Leader::bind(group).await?),
+        Some(group) => {
+            let group = group.clone();
+            let intake = intake_tx.clone();
+            supervise::spawn("sync follower", s.on_task_panic, move || {
+                let (group, intake) = (group.clone(), intake.clone());
+                async move {
+                    if let Err(e) = sync::follow(group, intake).await {
+                        error!("Stopped following the sync group: {e:?}");
+                    }
+                }
+            });
+            None
+        }
+        None => None,
+    };
+
+    // Weak, so a macro that might never run doesn't keep the intake open
+    let macro_intake = intake_tx.downgrade();
+    #[cfg(feature = "test-hooks")]
+    hooks::install(intake_tx.clone());
+    drop(intake_tx);
+
+    if let Some(schedule) = s.display_schedule.clone() {
+        let tx = tx.clone();
+        supervise::spawn("display schedule", s.on_task_panic, move || {
+            schedule::run(schedule.clone(), tx.clone())
+        });
+    }
+
+    let macros = s.macros.clone();
+    let macro_step = s.macro_step_ms.map_or(MACRO_STEP_DEFAULT, Duration::from_millis);
+    let mut running_macro: Option<tokio::task::JoinHandle<()>> = None;
+
+    let playback_buttons = buttons_tx.clone();
+    let acks = acks_tx.clone();
+    let closing = closing_tx.clone();
+    let blank_tx = tx.clone();
+    #[cfg(feature = "gpio")]
+    let mut relays = gpio::Relays::new(&s)?;
+    #[cfg(feature = "gpio")]
+    relays.reset_on_exit();
+    // Killed outright the exit hooks wouldn't run
+    #[cfg(feature = "gpio")]
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminations) => {
+            tokio::spawn(async move {
+                terminations.recv().await;
+                supervise::terminate()
+            });
+        }
+        Err(e) => error!("Can't switch the relays off on SIGTERM: {e:?}"),
+    }
+
+    supervise::once("playback", async move {
+        let mut playlist = LinkedList::new();
+
+        s.playlist
+            .iter()
+            .rev()
+            .for_each(|frag| playlist.push_front(frag.clone()));
+
+        let mut cursor = playlist.cursor_front();
+        let mut order = Order::new(s.order, s.order_seed, playlist.len());
+
+        set_launch_option(&mpv, "audio-device", AUDIO_DEVICE).await;
+        if let Some(rotation_deg) = s.rotation_deg {
+            set_launch_option(&mpv, "video-rotate", rotation_deg).await;
+        }
+        if s.fadeout_precise == Some(true) {
+            set_launch_option(&mpv, "hr-seek", "yes").await;
+        }
+
+        // Only now does mpv report what the launch options settled on
+        let summary: Vec<String> = diagnostics(&s, &mpv)
+            .into_iter()
+            .map(|(field, value)| format!("{field:>14}: {value}"))
+            .collect();
+        info!("Starting up with\n{}", summary.join("\n"));
+
+        let saved = s
+            .state_file
+            .as_deref()
+            .filter(|_| s.persist_volume)
+            .and_then(|path| persist::Persisted::load(path).volume);
+        if let Some(saved) = saved {
+            info!("Picking up the saved volume {saved}");
+        }
+        let volume =
+            s.clamp_volume(saved.unwrap_or_else(|| s.initial_volume.map_or(100.0, f64::from)));
+        mpv.set("volume", volume).or_log();
+
+        if let Some(ready) = display_ready {
+            let left = ready.saturating_duration_since(Instant::now());
+            info!("Giving the display another {left:?} to come up");
+            tokio::time::sleep_until(ready).await;
+        }
+        if s.splash.is_some() {
+            info!("Startup finished, replacing the splash");
+        }
+        let starts = if s.continuous {
+            info!("Continuous mode, mpv plays through the playlist by itself");
+            load_continuous(&mpv, &s.playlist)
+        } else {
+            cursor = skip_inactive(
+                &playlist,
+                cursor,
+                &mut order,
+                false,
+                s.end_step() == EndStep::StayOnLast,
+                chrono::Local::now().date_naive(),
+            );
+            playback_state.lock().await.current = cursor.index().unwrap_or(0);
+            mpv.replace(&cursor.current().unwrap().static_, true).or_log();
+            Vec::new()
+        };
+        #[cfg(feature = "gpio")]
+        relays.drive(cursor.current().unwrap().gpio_high.as_deref().unwrap_or_default());
+
+        if let Some(expected) = s.fs_screen_name.clone() {
+            let mpv = mpv.clone();
+            let (screen, retry) = (s.fs_screen, s.fs_screen_retry);
+            tokio::spawn(async move {
+                // The window only lands somewhere once the first file is up
+                tokio::time::sleep(SCREEN_CHECK_DELAY).await;
+                let landed = mpv.get_property::<String>("display-names").unwrap_or_default();
+                if on_display(&landed, &expected) {
+                    info!("Playing on {expected}");
+                    return;
+                }
+                warn!("Expected to play on {expected}, but mpv is on {landed:?}");
+                if retry && let Some(screen) = screen {
+                    info!("Moving back to screen {screen}");
+                    mpv.set_property("fs-screen", screen).ok();
+                    mpv.set_property("fullscreen", false).ok();
+                    mpv.set_property("fullscreen", true).ok();
+                }
+            });
+        }
+
+        if s.continuous {
+            if step_continuous(&mpv, &s, &starts, &acks, rx, urgent_rx, &playback_state).await {
+                restart(&mpv, &s, &closing).await;
+            }
+            return;
+        }
+
+        let mut last_transition: Option<Instant> = None;
+        let mut display_off = false;
+        let mut scheduled_off = false;
+        // osd-level and keep-open from before `pause_hold` held the frame
+        let mut held: Option<(i64, String)> = None;
+        // Blanked or idling by `sleep_action` with the cursor left where it was
+        let mut asleep = false;
+        // Last time a fadeout got played, for `post_fadeout_lockout_ms`
+        let mut faded_at: Option<Instant> = None;
+        let mut pending_pack: Option<Vec<Fragment>> = None;
+        // Transition that put a black fragment up, its timer only counts while it's still up
+        let mut blank: Option<u64> = None;
+        // Fragment and position Sleep left, for `sleep_undo_sec`
+        let mut slept_from: Option<(Instant, usize, f64)> = None;
+        // Where fragments were left, for `resume_on_return`
+        let mut returns: HashMap<usize, (Instant, f64)> = HashMap::new();
+        // Fragments left behind, for `prev_action: history`
+        let mut visited: Vec<usize> = Vec::new();
+
+        loop {
+            let queued = tokio::select! {
+                biased;
+                Some(queued) = urgent_rx.recv() => queued,
+                Some(queued) = rx.recv() => queued,
+                else => break,
+            };
+            let Queued {
+                seq,
+                at,
+                source,
+                command: cmd,
+            } = queued;
+            if s.manual_only && matches!(source, Source::Timer) {
+                info!("Manual only, dropping {cmd:?} from a timer");
+                continue;
+            }
+            let cmd = match cmd {
+                Command::BlankOver(of) if blank == Some(of) => {
+                    info!("Black hold is over, moving on");
+                    Command::Next
+                }
+                Command::BlankOver(_) => continue,
+                cmd => cmd,
+            };
+            playback_state.lock().await.record(source, &cmd);
+            if let Command::Restart = cmd {
+                restart(&mpv, &s, &closing).await;
+            }
+            if let Command::ForceButtonState(forced) = &cmd {
+                match forced {
+                    Some(button_state) => info!("Forcing button state {button_state:?}"),
+                    None => info!("Back to automatic button state"),
+                }
+                let signal = playback_state.lock().await.force(forced.clone());
+                if let Some(button_state) = signal {
+                    playback_buttons.send(button_state).ok();
+                }
+                continue;
+            }
+
+            if let Command::HoldAwake(hold) = cmd {
+                info!("{} the exhibit awake", if hold { "Holding" } else { "Releasing" });
+                playback_state.lock().await.held_awake = hold;
+                continue;
+            }
+
+            if let Command::ToggleMute = cmd {
+                let muted = !mpv.get_property::<bool>("mute").unwrap_or(false);
+                info!("{} the audio", if muted { "Muting" } else { "Unmuting" });
+                mpv.set("mute", muted).or_log();
+                let signal = {
+                    let mut state = playback_state.lock().await;
+                    state.muted = muted;
+                    state.shown().filter(|_| !state.buffering)
+                };
+                if let Some(button_state) = signal {
+                    playback_buttons.send(button_state).ok();
+                }
+                continue;
+            }
+
+            if intro_blocks(&cmd, playback_state.lock().await.intro_locked, held.is_some()) {
+                info!("Intro can't be skipped, ignoring {cmd:?}");
+                continue;
+            }
+
+            // Lands like GoTo, intro and all
+            let cmd = match cmd {
+                Command::Skip(steps) => {
+                    let index = skip_from(cursor.index().unwrap_or(0), steps, playlist.len());
+                    info!("Skipping {steps} fragments");
+                    Command::GoTo(index)
+                }
+                cmd => cmd,
+            };
+
+            if let Command::Reset = cmd {
+                info!("Resetting: clearing overrides and history");
+                let signal = {
+                    let mut state = playback_state.lock().await;
+                    state.history.clear();
+                    state.held_awake = false;
+                    state.muted = false;
+                    state.force(None)
+                };
+                if let Some(button_state) = signal {
+                    playback_buttons.send(button_state).ok();
+                }
+                info!("Resetting: restoring volume and player options");
+                set_volume(&mpv, &s, s.initial_volume.map_or(100.0, f64::from));
+                if let Some((osd_level, _)) = held.take() {
+                    mpv.set("osd-level", osd_level).or_log();
+                }
+                if s.allow_fullscreen_toggle {
+                    mpv.set("fullscreen", true).or_log();
+                }
+                mpv.set("pause", false).or_log();
+                slept_from = None;
+                returns.clear();
+                visited.clear();
+                order.reroll();
+                info!("Resetting: rereading the playlist and serial ports");
+                reload.notify_one();
+                match Settings::new() {
+                    Ok(reloaded) => pending_pack = Some(reloaded.playlist),
+                    Err(e) => error!("Failed to reread the playlist, keeping this one: {e:?}"),
+                }
+                info!("Resetting: going back to the first fragment");
+            }
+
+            if let Command::LoadPack(dir) = &cmd {
+                match settings::load_pack(std::path::Path::new(dir)) {
+                    Ok(pack) => {
+                        info!(
+                            "Loaded pack {dir} with {} fragments, switching at the next transition",
+                            pack.len()
+                        );
+                        pending_pack = Some(pack);
+                    }
+                    Err(e) => error!("Failed to load pack {dir}: {e}"),
+                }
+                continue;
+            }
+
+            // Falls through, the transition starts the playlist from its first fragment
+            if let Command::SwitchPlaylist(name) = &cmd {
+                match s.named_playlist(name) {
+                    Ok(named) => {
+                        info!("Switching to playlist {name}");
+                        pending_pack = Some(named);
+                    }
+                    Err(e) => {
+                        error!("Failed to switch playlists: {e}");
+                        continue;
+                    }
+                }
+            }
+
+            if let Command::Identify = cmd {
+                let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+                    .map(|hostname| hostname.trim().to_string())
+                    .unwrap_or_else(|_| "unknown host".to_string());
+                let duration = s
+                    .identify_ms
+                    .map_or(IDENTIFY_DEFAULT, Duration::from_millis);
+                info!("Identifying as {hostname} for {duration:?}");
+                mpv.command(
+                    "show-text",
+                    &[
+                        hostname.as_str(),
+                        duration.as_millis().to_string().as_str(),
+                    ],
+                )
+                .ok();
+                playback_buttons.send(ButtonState::Blinking).ok();
+                let state = playback_state.clone();
+                let buttons = playback_buttons.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(duration).await;
+                    let state = state.lock().await;
+                    if !state.buffering
+                        && let Some(button_state) = state.shown()
+                    {
+                        buttons.send(button_state).ok();
+                    }
+                });
+                continue;
+            }
+
+            if let Command::DisplayPower(on) = cmd {
+                scheduled_off = !on;
+                if on && display_off {
+                    if let Some(hook) = &s.screen_on_cmd {
+                        info!("Turning the display on for the day");
+                        run_hook(hook).await;
+                    }
+                    display_off = false;
+                }
+                if on {
+                    continue;
+                }
+            }
+
+            if let Command::Prev = cmd
+                && s.prev_action == PrevAction::Noop
+            {
+                info!("Prev does nothing here");
+                continue;
+            }
+
+            if adjust_player(&mpv, &s, &cmd) {
+                continue;
+            }
+
+            // Sleep during the off window puts a woken display back to sleep
+            if scheduled_off
+                && (!s.allow_wake_when_off
+                    || matches!(cmd, Command::Sleep | Command::DisplayPower(_)))
+            {
+                if !display_off {
+                    match &s.screen_off_cmd {
+                        Some(hook) => {
+                            info!("Turning the display off for the night");
+                            run_hook(hook).await;
+                            display_off = true;
+                        }
+                        None => warn!("No screen_off_cmd configured, display stays on"),
+                    }
+                }
+                continue;
+            }
+
+            if let Command::GoTo(index) = cmd
+                && index >= playlist.len()
+            {
+                warn!("No fragment {index} in a playlist of {}, ignoring", playlist.len());
+                continue;
+            }
+
+            if let Some(rate) = s.max_transitions_per_sec.filter(|rate| *rate > 0.0) {
+                let interval = Duration::from_secs_f32(1.0 / rate);
+                let wait = last_transition.map_or(Duration::ZERO, |at| {
+                    interval.saturating_sub(at.elapsed())
+                });
+                if !wait.is_zero() {
+                    info!("Holding transition for {wait:?} to stay under {rate} per second");
+                    tokio::time::sleep(wait).await;
+                }
+                last_transition = Some(Instant::now());
+            }
+
+            // Whatever got this far is acted upon, so the firmware can stop retransmitting
+            if let Source::Serial(port) = source
+                && let Some(ack) = s.serial_ack
+            {
+                acks.send((port, ack as u8)).ok();
+            }
+
+            // Sleep dispatches before the display wakes, it has no business waking it
+            if let Command::Sleep = cmd {
+                // Nothing's lit while asleep, rewinding drives the home fragment's pins again
+                #[cfg(feature = "gpio")]
+                relays.reset();
+                match s.sleep_action {
+                    SleepAction::RewindToHome => {}
+                    SleepAction::PauseHold => {
+                        if held.is_none() {
+                            info!("Holding the current frame");
+                            let osd_level = read_property::<i64>(&mpv, "osd-level", 1).await;
+                            let keep_open =
+                                read_property(&mpv, "keep-open", "no".to_string()).await;
+                            mpv.set("keep-open", "yes").or_log();
+                            mpv.set("osd-level", 0i64).or_log();
+                            mpv.set("pause", true).or_log();
+                            held = Some((osd_level, keep_open));
+                        }
+                        continue;
+                    }
+                    SleepAction::BlankScreen => {
+                        if !asleep {
+                            match &s.screen_off_cmd {
+                                Some(hook) => {
+                                    info!("Turning the display off until the next command");
+                                    run_hook(hook).await;
+                                    display_off = true;
+                                }
+                                None => {
+                                    info!("Going black until the next command");
+                                    mpv.replace(settings::BLANK_SOURCE, true).or_log();
+                                    mpv.clear().or_log();
+                                }
+                            }
+                            asleep = true;
+                        }
+                        continue;
+                    }
+                    SleepAction::IdleFragment => match &s.idle_clip {
+                        Some(clip) => {
+                            if !asleep {
+                                info!("Looping idle clip {clip} until the next command");
+                                mpv.replace(clip, true).or_log();
+                                mpv.clear().or_log();
+                                asleep = true;
+                            }
+                            continue;
+                        }
+                        None => warn!("No idle_clip configured, rewinding to home instead"),
+                    },
+                }
+            }
+
+            if display_off {
+                if let Some(hook) = &s.screen_on_cmd {
+                    info!("Turning the display back on");
+                    run_hook(hook).await;
+                }
+                display_off = false;
+            }
+
+            if let Some((osd_level, keep_open)) = held.take() {
+                info!("Waking from the held frame");
+                mpv.set("osd-level", osd_level).or_log();
+                mpv.set("keep-open", keep_open.as_str()).or_log();
+                mpv.set("pause", false).or_log();
+            }
+
+            blank = None;
+            if let Some((superseded, _)) = playback_state.lock().await.awaiting.replace((seq, at)) {
+                info!("Command #{superseded} was overtaken by #{seq} before anything showed");
+            }
+
+            if let Some((slept_at, index, position)) = slept_from.take()
+                && matches!(cmd, Command::Next | Command::Prev)
+                && s.sleep_undo_sec
+                    .is_some_and(|undo| slept_at.elapsed() < Duration::from_secs(undo as u64))
+            {
+                info!("Woken right after sleeping, resuming fragment {index} at {position:.1}s");
+                cursor = cursor_at(&playlist, Some(index));
+                let fragment = cursor.current().unwrap();
+                playback_state.lock().await.current = index;
+                if let Some(leader) = &leader {
+                    leader.announce(index).await;
+                }
+                let keep_open = fragment.keep_open.unwrap_or(false);
+                mpv.set("keep-open", if keep_open { "yes" } else { "no" }).or_log();
+                #[cfg(feature = "gpio")]
+                relays.drive(fragment.gpio_high.as_deref().unwrap_or_default());
+                let seek = (fragment.static_.clone(), position);
+                playback_state.lock().await.seek_on_load = Some(seek);
+                mpv.replace(&fragment.static_, true).or_log();
+                mpv.clear().or_log();
+                continue;
+            }
+
+            // A second Next cuts the transition clip short
+            if let Command::Next = cmd
+                && let Some(clip) = &s.transition_clip
+                && mpv.get_property::<String>("path").is_ok_and(|path| &path == clip)
+            {
+                info!("Skipping the transition clip");
+                if let Err(e) = mpv.command("playlist-next", &["force"]) {
+                    error!("Failed to skip the transition clip: {e:?}");
+                }
+                continue;
+            }
+
+            if s.force_fade_on_cut {
+                info!("Fading to black before the cut");
+                fade_to_black(&mpv, CUT_FADE).await;
+            }
+
+            if let Some(index) = cursor.index()
+                && s.resumes(cursor.current().unwrap())
+                && let Some(position) = static_position(&mpv, cursor.current().unwrap())
+            {
+                returns.insert(index, (Instant::now(), position));
+            }
+
+            let mut replaced = false;
+            let (mut faded, mut introduced) = (false, false);
+            // Whatever plays while asleep isn't the fragment, its fadeout doesn't apply
+            let woke = std::mem::take(&mut asleep);
+            // Right after a fadeout mpv is still settling, a quick Next just cuts
+            let settling = faded_at
+                .zip(s.post_fadeout_lockout_ms)
+                .is_some_and(|(at, lockout)| at.elapsed() < Duration::from_millis(lockout));
+            if settling {
+                info!("Within the post-fadeout lockout, skipping fadeouts");
+            }
+
+            info!("Preparing to play next fragment...");
+            // No current fragment while idling past the end of the playlist
+            if let Some(fadeouts) = cursor
+                .current()
+                .filter(|_| !woke && !settling)
+                .and_then(|fragment| fragment.fadeout.as_ref())
+            {
+                info!("Current fragment has fadeout, processing...");
+                let loops = read_property::<i32>(&mpv, "remaining-file-loops", 0).await;
+                info!("Loops left: {loops}");
+                let mut playback_time = read_property::<f32>(&mpv, "playback-time", 0.0).await;
+                let duration = read_property::<f32>(&mpv, "duration", 0.0).await;
+                if s.fadeout_precise == Some(true) {
+                    let fps = read_property::<f32>(&mpv, "container-fps", 0.0).await;
+                    playback_time = settings::align_to_frame(playback_time, duration, fps);
+                }
+                let mut maybe_fadeout: Option<&settings::Fadeout> = None;
+                if loops == -1 {
+                    maybe_fadeout = fadeouts
+                        .iter()
+                        .find(|video| video.threshold(duration).is_none());
+                } else if let Some(fadeout) = fadeouts.iter().find(|timing| {
+                    playback_time <= timing.threshold(duration).unwrap_or(std::f32::MAX)
+                }) {
+                    maybe_fadeout = Some(fadeout);
+                }
+                info!("Playback time: {playback_time}");
+                if let Some(fadeout) = maybe_fadeout {
+                    info!("Replacing with outro");
+                    replaced = true;
+                    faded = true;
+                    faded_at = Some(Instant::now());
+                    mpv.replace(&fadeout.video, false).or_log();
+                    mpv.clear().or_log();
+                }
+            }
+
+            if let Command::Next = cmd
+                && let Some(clip) = s.transition_clip.as_ref().filter(|_| !replaced)
+            {
+                info!("Replacing with transition clip {clip}");
+                replaced = true;
+                mpv.replace(clip, false).or_log();
+                mpv.clear().or_log();
+            }
+
+            info!("Moving playlist position...");
+            let left = cursor.index();
+
+            // The new pack starts over from its first fragment, whatever the command was
+            let switched = pending_pack.is_some();
+            if let Some(pack) = pending_pack.take() {
+                info!("Switching to the new pack");
+                playlist = pack.into_iter().collect();
+                order = Order::new(s.order, s.order_seed, playlist.len());
+                returns.clear();
+                visited.clear();
+                slept_from = None;
+                cursor = playlist.cursor_front();
+                playback_state.lock().await.playlist = Arc::new(playlist.iter().cloned().collect());
+            }
+
+            let mut skip_intro = false;
+            match cmd {
+                _ if switched => {}
+                Command::Next => {
+                    let next = order.after(cursor.index());
+                    cursor = cursor_at(&playlist, next);
+                    if next.is_none() {
+                        info!("Reached the end of playlist");
+                        match s.end_step() {
+                            EndStep::Wrap { outro } => {
+                                info!("Wrapping over");
+                                cursor = cursor_at(&playlist, Some(order.first()));
+                                if let Some(outro) = outro {
+                                    if replaced {
+                                        info!("Queuing playlist outro {outro}");
+                                        mpv.queue(outro, false).or_log();
+                                    } else {
+                                        info!("Replacing with playlist outro {outro}");
+                                        replaced = true;
+                                        mpv.replace(outro, false).or_log();
+                                        mpv.clear().or_log();
+                                    }
+                                }
+                            }
+                            EndStep::StayOnLast => {
+                                info!("Staying on the last fragment");
+                                cursor = cursor_at(&playlist, Some(order.last()));
+                                skip_intro = true;
+                            }
+                            EndStep::Idle(clip) => {
+                                // The cursor stays past the end, so the next Next starts over
+                                if replaced {
+                                    info!("Queuing idle clip {clip}");
+                                    mpv.queue(clip, true).or_log();
+                                } else {
+                                    info!("Replacing with idle clip {clip}");
+                                    mpv.replace(clip, true).or_log();
+                                    mpv.clear().or_log();
+                                }
+                                playback_state.lock().await.current = 0;
+                                #[cfg(feature = "gpio")]
+                                relays.reset();
+                                continue;
+                            }
+                            EndStep::DisplayOff => {
+                                if let Some(hook) = &s.screen_off_cmd {
+                                    info!("Turning the display off");
+                                    run_hook(hook).await;
+                                    display_off = true;
+                                } else {
+                                    warn!("No screen_off_cmd configured, wrapping over instead");
+                                }
+                                cursor = cursor_at(&playlist, Some(order.first()));
+                            }
+                        }
+                    }
+                }
+                Command::Prev => match s.prev_action {
+                    PrevAction::Restart | PrevAction::Noop => info!("Actually not moving at all"),
+                    PrevAction::Home => {
+                        info!("Going back to the first fragment");
+                        cursor = playlist.cursor_front();
+                    }
+                    PrevAction::MovePrev => {
+                        cursor = cursor_at(&playlist, order.before(cursor.index()));
+                    }
+                    PrevAction::History => match visited.pop() {
+                        Some(index) => {
+                            info!("Going back to fragment {index}");
+                            cursor = cursor_at(&playlist, Some(index));
+                        }
+                        None => info!("Nothing visited before, staying"),
+                    },
+                },
+                Command::Reset => cursor = playlist.cursor_front(),
+                Command::GoTo(index) => {
+                    info!("Going to fragment {index}");
+                    cursor = cursor_at(&playlist, Some(index));
+                    // Going home on inactivity is for the next visitor, like a wake from sleep
+                    if index == 0 && matches!(source, Source::Timer) {
+                        returns.clear();
+                        visited.clear();
+                        order.reroll();
+                    }
+                }
+                Command::Sleep => {
+                    slept_from = cursor
+                        .index()
+                        .filter(|index| s.sleep_undo_sec.is_some() && *index != 0)
+                        .map(|index| {
+                            let position = static_position(&mpv, cursor.current().unwrap());
+                            (Instant::now(), index, position.unwrap_or(0.0))
+                        });
+                    returns.clear();
+                    visited.clear();
+                    info!("Moving cursor to the start");
+                    cursor = playlist.cursor_front();
+                    order.reroll();
+                    if playback_state.lock().await.paused {
+                        info!("Player is paused, resuming for the first fragment");
+                        mpv.set("pause", false).or_log();
+                    }
+                }
+                Command::VolumeUp
+                | Command::VolumeDown
+                | Command::SetVolume(_)
+                | Command::ForceButtonState(_)
+                | Command::NextAudioDevice
+                | Command::ToggleFullscreen
+                | Command::DisplayPower(_)
+                | Command::Identify
+                | Command::LoadPack(_)
+                | Command::SetSpeed(_)
+                | Command::ResetSpeed
+                | Command::BlankOver(_)
+                | Command::HoldAwake(_)
+                | Command::SwitchPlaylist(_)
+                | Command::Skip(_)
+                | Command::ToggleMute
+                | Command::RunMacro(_)
+                | Command::Restart => {}
+            };
+            if cursor.current().is_none() {
+                cursor.move_next();
+            }
+            // Wherever the command landed, be it a GoTo, a Skip or going home
+            cursor = skip_inactive(
+                &playlist,
+                cursor,
+                &mut order,
+                matches!(cmd, Command::Prev),
+                s.end_step() == EndStep::StayOnLast,
+                chrono::Local::now().date_naive(),
+            );
+            // Going back through the history mustn't record the way back
+            if let Some(left) = left
+                && cursor.index() != Some(left)
+                && !(matches!(cmd, Command::Prev) && s.prev_action == PrevAction::History)
+            {
+                if visited.len() == VISITED_LEN {
+                    visited.remove(0);
+                }
+                visited.push(left);
+            }
+            playback_state.lock().await.current = cursor.index().unwrap_or(0);
+            if let Some(leader) = &leader {
+                leader.announce(cursor.index().unwrap_or(0)).await;
+            }
+            #[cfg(feature = "gpio")]
+            relays.drive(cursor.current().unwrap().gpio_high.as_deref().unwrap_or_default());
+
+            let resume_at = cursor
+                .index()
+                .and_then(|index| returns.remove(&index))
+                .filter(|(left_at, _)| {
+                    s.resume_max_age_sec
+                        .is_none_or(|max| left_at.elapsed() < Duration::from_secs(max))
+                })
+                .map(|(_, position)| position);
+            if resume_at.is_some() {
+                skip_intro = true;
+            }
+
+            // mpv keeps mute across files by itself, only fragments that insist change it
+            let muted = playback_state.lock().await.muted;
+            let mute = cursor.current().unwrap().mute.unwrap_or(muted);
+            mpv.set("mute", mute).or_log();
+
+            if !s.preserve_speed && read_property::<f64>(&mpv, "speed", 1.0).await != 1.0 {
+                set_speed(&mpv, &s, 1.0);
+            }
+
+            // Holds the intro's last frame until the static gets queued
+            let keep_open = cursor.current().unwrap().keep_open.unwrap_or(false);
+            mpv.set("keep-open", if keep_open { "yes" } else { "no" }).or_log();
+
+            if let Some(intro) = cursor
+                .current()
+                .unwrap()
+                .intro
+                .as_ref()
+                .filter(|_| !skip_intro)
+            {
+                introduced = true;
+                if replaced {
+                    info!("Next fragment has intro. Queuing {intro}");
+                    mpv.queue(intro, false).or_log();
+                } else {
+                    info!("Next fragment has intro. Replacing with {intro}");
+                    replaced = true;
+                    mpv.replace(intro, false).or_log();
+                    mpv.clear().or_log();
+                }
+            }
+            let next = &cursor.current().unwrap().static_;
+            // Seeking once it's loaded, `start` would send every loop back there
+            playback_state.lock().await.seek_on_load =
+                resume_at.map(|position| (next.clone(), position));
+            if let Some(position) = resume_at {
+                info!("Resuming {next} at {position:.1}s");
+                if replaced {
+                    mpv.queue(next, true).or_log();
+                } else {
+                    mpv.replace(next, true).or_log();
+                    mpv.clear().or_log();
+                }
+            } else if replaced {
+                info!("Queuing next loop fragment {next}");
+                mpv.queue(next, true).or_log();
+            } else {
+                info!("Replacing with  next loop fragment {next}");
+                mpv.replace(next, true).or_log();
+                mpv.clear().or_log();
+            }
+            if let Some(path) = &s.transition_log {
+                let transition = transitions::Transition {
+                    from: left,
+                    to: cursor.index().unwrap_or(0),
+                    source,
+                    command: &cmd,
+                    fadeout: faded,
+                    intro: introduced,
+                    file: next,
+                };
+                transitions::record(path, &transition);
+            }
+
+            // With `manual_only` black holds until somebody moves on
+            let fragment = cursor.current().unwrap();
+            if fragment.is_blank() && !s.manual_only {
+                let hold = fragment
+                    .blank_sec
+                    .map_or(BLANK_DEFAULT, Duration::from_secs_f32);
+                blank = Some(seq);
+                let blank_tx = blank_tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(hold).await;
+                    blank_tx.send(Queued::new(Source::Timer, Command::BlankOver(seq))).await.ok();
+                });
+            }
+        }
+    });
+
+    // Timers keep the player going once every source is gone, or when none was configured
+    let mut sources_gone = false;
+    loop {
+        // Re-read every time round, the fragment might have changed since
+        let (advance_every, sleep_after) = if idle_off {
+            (None, None)
+        } else {
+            let state = state.lock().await;
+            state.playlist.get(state.current).map_or((None, None), |fragment| {
+                (
+                    fragment.idle_advance_sec.map(Duration::from_secs),
+                    fragment.idle_sleep_sec.map(Duration::from_secs),
+                )
+            })
+        };
+        let pending = idle.next(advance_every, sleep_after);
+        let intake = async {
+            if sources_gone {
+                std::future::pending().await
+            } else {
+                intake_rx.recv().await
+            }
+        };
+        let received = match &pending {
+            Some((deadline, _)) => timeout_at(*deadline, intake).await,
+            None => Ok(intake.await),
+        };
+        let (source, command) = match received {
+            Ok(Some(received)) => {
+                idle.input();
+                received
+            }
+            Ok(None) => {
+                warn!("All command sources are gone, carrying on with the timers");
+                sources_gone = true;
+                continue;
+            }
+            Err(_) => {
+                let (_, idle_command) = pending.expect("only a pending timer elapses");
+                if let Command::Sleep = idle_command {
+                    let (held_awake, held) = {
+                        let state = state.lock().await;
+                        let held = state
+                            .playlist
+                            .get(state.current)
+                            .is_some_and(|fragment| fragment.allow_sleep == Some(false));
+                        (state.held_awake, held)
+                    };
+                    if held_awake {
+                        info!("Held awake, trying again later");
+                        idle.postpone();
+                        continue;
+                    }
+                    if held {
+                        info!("Current fragment doesn't allow sleeping, trying again later");
+                        idle.postpone();
+                        continue;
+                    }
+                }
+                idle.fired(&idle_command);
+                // A visitor pressing something right as Sleep fires wins over the Sleep
+                if let Command::Sleep = idle_command
+                    && let Ok(Some(received)) = timeout(SLEEP_COALESCE, intake_rx.recv()).await
+                {
+                    info!("{:?} arrived as the inactivity Sleep fired, dropping it", received.1);
+                    idle.input();
+                    received
+                } else {
+                    (Source::Timer, idle_command)
+                }
+            }
+        };
+        // Anything a visitor or operator sends cuts a running macro short
+        if !matches!(source, Source::Macro | Source::Timer)
+            && let Some(running) = running_macro.take()
+            && !running.is_finished()
+        {
+            info!("{command:?} arrived, aborting the running macro");
+            running.abort();
+        }
+        if let Command::RunMacro(name) = &command {
+            if let Source::Macro = source {
+                warn!("Macros can't run other macros, skipping {name}");
+                continue;
+            }
+            let Some(steps) = macros.get(name).cloned() else {
+                warn!("No macro named {name}");
+                continue;
+            };
+            let Some(intake) = macro_intake.upgrade() else {
+                continue;
+            };
+            info!("Running macro {name}, {} steps", steps.len());
+            running_macro = Some(tokio::spawn(async move {
+                for (i, step) in steps.into_iter().enumerate() {
+                    if i > 0 {
+                        tokio::time::sleep(macro_step).await;
+                    }
+                    if intake.send((Source::Macro, step)).await.is_err() {
+                        return;
+                    }
+                }
+            }));
+            continue;
+        }
+        if let Command::Sleep = command {
+            match urgent_tx.try_send(Queued::new(source, command)) {
+                Err(TrySendError::Full(_)) => info!("Sleep is already pending"),
+                Err(TrySendError::Closed(_)) => {
+                    error!("Something's gone terribly wrong: playback task is gone");
+                    return Err(anyhow!("playback task is gone"));
+                }
+                Ok(()) => {}
+            }
+        } else {
+            let queued = match tx.try_send(Queued::new(source, command)) {
+                Ok(()) => continue,
+                // Piling transitions up behind a slow loadfile only makes every jump late
+                Err(TrySendError::Full(queued))
+                    if matches!(queued.command, Command::Next | Command::Prev) =>
+                {
+                    info!("Playback is busy, dropping {:?}", queued.command);
+                    continue;
+                }
+                Err(TrySendError::Full(queued)) => queued,
+                Err(TrySendError::Closed(_)) => {
+                    error!("Something's gone terribly wrong: playback task is gone");
+                    return Err(anyhow!("playback task is gone"));
+                }
+            };
+            if let Err(e) = tx.send(queued).await {
+                error!("Something's gone terribly wrong: {e:?}");
+                return Err(anyhow!(e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> (Vec<Command>, BytesMut) {
+        let mut codec = LineCodec::default();
+        let mut src = BytesMut::from(bytes);
+        let mut decoded = Vec::new();
+        while let Some(command) = codec.decode(&mut src).unwrap() {
+            decoded.push(command);
+        }
+        (decoded, src)
+    }
+
+    #[test]
+    fn lines_decode_to_their_last_byte() {
+        let (decoded, rest) = decode(b"n\np\r\ns\nr\n");
+        assert!(matches!(
+            decoded[..],
+            [Command::Next, Command::Prev, Command::Sleep, Command::Reset]
+        ));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn press_annotations_follow_the_mapping() {
+        let (decoded, _) = decode(b"1:short\n1:long\r\n");
+        assert!(matches!(decoded[..], [Command::Next, Command::Sleep]));
+    }
+
+    #[test]
+    fn unknown_bytes_and_blank_lines_get_skipped() {
+        let (decoded, _) = decode(b"\nx\n\r\nn\n");
+        assert!(matches!(decoded[..], [Command::Next]));
+    }
+
+    #[test]
+    fn a_partial_line_waits_for_the_rest() {
+        let (decoded, rest) = decode(b"n\np");
+        assert!(matches!(decoded[..], [Command::Next]));
+        assert_eq!(&rest[..], b"p");
+    }
+
+    #[test]
+    fn skips_wrap_around_both_ends() {
+        assert_eq!(skip_from(1, 2, 5), 3);
+        assert_eq!(skip_from(4, 2, 5), 1);
+        assert_eq!(skip_from(0, -1, 5), 4);
+        assert_eq!(skip_from(0, 3, 0), 0);
+    }
+
+    #[test]
+    fn skips_are_capped() {
+        assert_eq!(skip_from(0, i32::MAX, 1000), MAX_SKIP as usize);
+        assert_eq!(skip_from(0, i32::MIN, 1000), 1000 - MAX_SKIP as usize);
+    }
+
+    #[test]
+    fn audio_devices_cycle_in_order() {
+        let devices: Vec<String> = ["auto", "hdmi", "jack"].map(String::from).to_vec();
+        assert_eq!(next_audio_device(&devices, "auto").map(String::as_str), Some("hdmi"));
+        assert_eq!(next_audio_device(&devices, "jack").map(String::as_str), Some("auto"));
+        assert_eq!(next_audio_device(&devices, "gone").map(String::as_str), Some("auto"));
+        assert_eq!(next_audio_device(&[], "auto"), None);
+    }
+
+    #[test]
+    fn locked_intro_drops_next_until_it_ends() {
+        assert!(intro_blocks(&Command::Next, true, false));
+        assert!(intro_blocks(&Command::Prev, true, false));
+        assert!(intro_blocks(&Command::Skip(2), true, false));
+        assert!(!intro_blocks(&Command::Next, false, false));
+    }
+
+    #[test]
+    fn locked_intro_still_honours_sleep_and_goto() {
+        assert!(!intro_blocks(&Command::Sleep, true, false));
+        assert!(!intro_blocks(&Command::GoTo(0), true, false));
+    }
+
+    #[test]
+    fn waking_a_held_frame_gets_past_the_lock() {
+        assert!(!intro_blocks(&Command::Next, true, true));
+    }
+
+    /// Fragments that are active unless marked false, on a fixed day
+    fn playlist(active: &[bool]) -> LinkedList<Fragment> {
+        let fragment = |active: &bool| {
+            let until = if *active { "2100-01-01" } else { "2000-01-01" };
+            serde_json::from_value(serde_json::json!({ "static": "a.mp4", "active_until": until }))
+                .expect("a valid fragment")
+        };
+        active.iter().map(fragment).collect()
+    }
+
+    fn landed(active: &[bool], at: usize, backwards: bool, stay_on_last: bool) -> Option<usize> {
+        let playlist = playlist(active);
+        let mut order = Order::new(settings::OrderMode::Sequential, None, playlist.len());
+        let today = chrono::NaiveDate::from_ymd_opt(2026, 10, 14).unwrap();
+        let cursor = cursor_at(&playlist, Some(at));
+        skip_inactive(&playlist, cursor, &mut order, backwards, stay_on_last, today).index()
+    }
+
+    #[test]
+    fn inactive_fragments_get_passed_over_either_way() {
+        assert_eq!(landed(&[true, false, true], 1, false, false), Some(2));
+        assert_eq!(landed(&[true, false, true], 1, true, false), Some(0));
+        assert_eq!(landed(&[true, true], 1, false, false), Some(1));
+    }
+
+    #[test]
+    fn passing_over_the_end_follows_end_action() {
+        assert_eq!(landed(&[true, true, false], 2, false, false), Some(0));
+        assert_eq!(landed(&[true, true, false], 2, false, true), Some(1));
+    }
+
+    #[test]
+    fn nothing_active_plays_where_it_ends_up() {
+        assert!(landed(&[false, false], 0, false, false).is_some());
+    }
+
+    #[tokio::test]
+    async fn coalescing_drops_repeats_only() {
+        let (states, _) = broadcast::channel(8);
+        let (signalled, mut received) = broadcast::channel(8);
+        let coalescing = tokio::spawn(coalesce_buttons(
+            states.subscribe(),
+            signalled,
+            Duration::ZERO,
+        ));
+        let (both, locked) = (ButtonState::Both, ButtonState::Locked);
+        for state in [both.clone(), both.clone(), locked.clone(), both.clone()] {
+            states.send(state).unwrap();
+        }
+        drop(states);
+        coalescing.await.unwrap();
+        let mut sent = Vec::new();
+        while let Ok(state) = received.try_recv() {
+            sent.push(state);
+        }
+        assert_eq!(sent, [both.clone(), locked, both]);
+    }
+}
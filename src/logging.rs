@@ -0,0 +1,313 @@
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use chrono::{Local, NaiveDate};
+use ftail::{Config, Ftail};
+use log::{LevelFilter, Log, Metadata, Record, warn};
+use syslog::{BasicLogger, Facility, Formatter3164};
+use systemd_journal_logger::JournalLog;
+
+use crate::error::{CavempvError, Result};
+use crate::settings::{LogTarget, Settings};
+
+const IDENTIFIER: &str = "cavempv";
+/// Raises or lowers every sink at once, e.g. `CAVEMPV_LOG_LEVEL=trace`
+const LEVEL_VAR: &str = "CAVEMPV_LOG_LEVEL";
+/// A day's file past this gets closed early and a fresh one started
+const MAX_FILE_LEN: u64 = 100 * 1024 * 1024;
+/// Older daily files get deleted whenever a new one starts
+const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The daily file, reachable so `flush` can close it on demand
+static DAILY: OnceLock<Arc<DailyFile>> = OnceLock::new();
+
+struct Open {
+    day: NaiveDate,
+    path: PathBuf,
+    writer: LineWriter<File>,
+    written: u64,
+}
+
+/// One file per day in `log_dir`, standing in for ftail's own so it can be rotated
+struct DailyFile {
+    dir: PathBuf,
+    level: LevelFilter,
+    current: Mutex<Option<Open>>,
+}
+
+impl DailyFile {
+    fn open(&self, day: NaiveDate) -> std::io::Result<Open> {
+        let path = self.dir.join(format!("{day}.log"));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        // Only our own files expire, whatever else shares the directory stays
+        for old in newest_logs(&self.dir).into_iter().filter(|old| is_daily_log(old)) {
+            let expired = std::fs::metadata(&old)
+                .and_then(|meta| meta.modified())
+                .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > RETENTION));
+            if expired {
+                std::fs::remove_file(old).ok();
+            }
+        }
+        Ok(Open {
+            day,
+            path,
+            writer: LineWriter::new(file),
+            written,
+        })
+    }
+}
+
+/// Closes the file under a timestamped name, returning that name
+fn close(open: Open) -> std::io::Result<PathBuf> {
+    let Open {
+        day,
+        path,
+        mut writer,
+        ..
+    } = open;
+    writer.flush()?;
+    drop(writer);
+    let closed = path.with_file_name(format!("{day}-{}.log", Local::now().format("%H%M%S")));
+    std::fs::rename(&path, &closed)?;
+    Ok(closed)
+}
+
+impl Log for DailyFile {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = Local::now();
+        let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+        let full = current.as_ref().is_some_and(|open| open.written > MAX_FILE_LEN);
+        if full && let Some(open) = current.take() {
+            close(open).ok();
+        }
+        if current.as_ref().is_none_or(|open| open.day != now.date_naive()) {
+            *current = self.open(now.date_naive()).ok();
+        }
+        if let Some(open) = current.as_mut() {
+            let line = format!(
+                "{} {} {} {}\n",
+                now.format("%Y-%m-%d %H:%M:%S%.3f"),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            if open.writer.write_all(line.as_bytes()).is_ok() {
+                open.written += line.len() as u64;
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(open) = current.as_mut() {
+            open.writer.flush().ok();
+        }
+    }
+}
+
+/// What ftail gets handed, the file itself stays in `DAILY`
+struct Daily(Arc<DailyFile>);
+
+impl Log for Daily {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+fn daily_file(
+    dir: &Path,
+    level: LevelFilter,
+) -> impl Fn(Config) -> Box<dyn Log + Send + Sync> + use<> {
+    let daily = DAILY
+        .get_or_init(|| {
+            Arc::new(DailyFile {
+                dir: dir.to_path_buf(),
+                level,
+                current: Mutex::new(None),
+            })
+        })
+        .clone();
+    move |_| Box::new(Daily(daily.clone()))
+}
+
+/// Writes out whatever is buffered. With `rotate` the day's file is closed under a
+/// timestamped name and the next line starts a fresh one, the closed file is returned.
+pub fn flush(rotate: bool) -> std::io::Result<Option<PathBuf>> {
+    log::logger().flush();
+    let Some(daily) = DAILY.get().filter(|_| rotate) else {
+        return Ok(None);
+    };
+    let open = daily
+        .current
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    open.map(close).transpose()
+}
+
+/// Console and a rotated daily file unless `log_target` routes logs elsewhere.
+/// Falls back to the console alone when `log_dir` can't be written to,
+/// and to the default levels when the override doesn't parse.
+pub fn init(s: &Settings) -> Result<()> {
+    let (level, unparsable) = match override_level() {
+        Ok(level) => (level, None),
+        Err(e) => (None, Some(e)),
+    };
+    let console = level.unwrap_or(LevelFilter::Debug);
+    let file = level.unwrap_or(LevelFilter::Info);
+
+    let log_dir = Path::new(&s.log_dir);
+    let unwritable = match s.log_target {
+        None | Some(LogTarget::File) => writable(log_dir).err(),
+        _ => None,
+    };
+
+    match s.log_target {
+        _ if unwritable.is_some() => Ftail::new()
+            .formatted_console(console)
+            .init()
+            .map_err(failed)?,
+        None => Ftail::new()
+            .formatted_console(console)
+            .custom(daily_file(log_dir, file), file)
+            .init()
+            .map_err(failed)?,
+        Some(LogTarget::Console) => Ftail::new().formatted_console(console).init()
+            .map_err(failed)?,
+        Some(LogTarget::File) => Ftail::new()
+            .custom(daily_file(log_dir, file), file)
+            .init()
+            .map_err(failed)?,
+        Some(LogTarget::Journald) => {
+            JournalLog::new()
+                .map_err(failed)?
+                .with_syslog_identifier(IDENTIFIER.to_string())
+                .install()
+                .map_err(failed)?;
+            log::set_max_level(file);
+        }
+        Some(LogTarget::Syslog) => {
+            let logger = syslog::unix(Formatter3164 {
+                facility: Facility::LOG_DAEMON,
+                hostname: None,
+                process: IDENTIFIER.to_string(),
+                pid: std::process::id(),
+            })
+            .map_err(failed)?;
+            log::set_boxed_logger(Box::new(BasicLogger::new(logger))).map_err(failed)?;
+            log::set_max_level(file);
+        }
+    }
+
+    if let Some(e) = unwritable {
+        warn!("Can't write logs to {}: {e}, logging to the console only", s.log_dir);
+    }
+    if let Some(e) = unparsable {
+        warn!("Ignoring {e}, logging at the default levels");
+    }
+
+    Ok(())
+}
+
+/// Creates the directory if needed and checks a file can actually be written into it
+pub fn writable(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(".cavempv-write-test");
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(probe)
+}
+
+/// Whether this is one of the daily files, `2026-10-14.log` or a sibling rotated
+/// away from it such as `2026-10-14-153000.log`, rather than anything else kept there
+pub fn is_daily_log(path: &Path) -> bool {
+    let Some(stem) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".log"))
+    else {
+        return false;
+    };
+    let (day, rest) = stem.split_at_checked(10).unwrap_or((stem, ""));
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").is_ok()
+        && rest
+            .strip_prefix('-')
+            .map_or(rest.is_empty(), |suffix| {
+                !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit())
+            })
+}
+
+/// Files in the log directory, newest first
+pub fn newest_logs(dir: &Path) -> Vec<PathBuf> {
+    let Ok(read) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut logs: Vec<(SystemTime, PathBuf)> = read
+        .flatten()
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok().filter(|meta| meta.is_file())?;
+            Some((meta.modified().ok()?, entry.path()))
+        })
+        .collect();
+    logs.sort_by(|a, b| b.0.cmp(&a.0));
+    logs.into_iter().map(|(_, path)| path).collect()
+}
+
+fn override_level() -> std::result::Result<Option<LevelFilter>, String> {
+    match std::env::var(LEVEL_VAR) {
+        Ok(level) => level
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("{LEVEL_VAR}={level} ({e})")),
+        Err(_) => Ok(None),
+    }
+}
+
+fn failed(e: impl std::fmt::Display) -> CavempvError {
+    CavempvError::Logging(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daily_logs_and_their_rotations_count() {
+        assert!(is_daily_log(Path::new("logs/2026-10-14.log")));
+        assert!(is_daily_log(Path::new("2026-10-14-153000.log")));
+    }
+
+    #[test]
+    fn anything_else_in_the_log_dir_does_not() {
+        for other in [
+            "2026-10-14.txt",
+            "2026-13-01.log",
+            "2026-10-14-.log",
+            "2026-10-14-15h.log",
+            "2026-10-14x.log",
+            "mpv.log",
+            ".log",
+        ] {
+            assert!(!is_daily_log(Path::new(other)), "{other}");
+        }
+    }
+}
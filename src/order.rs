@@ -0,0 +1,138 @@
+use rand::{
+    SeedableRng,
+    rngs::StdRng,
+    seq::{IndexedRandom, SliceRandom},
+};
+
+use crate::settings::OrderMode;
+
+/// Decides which fragment Next lands on.
+/// The permutation keeps the first fragment as home and shuffles the rest,
+/// it stays fixed until the next Sleep re-rolls it.
+pub struct Order {
+    mode: OrderMode,
+    rng: StdRng,
+    tour: Vec<usize>,
+}
+
+impl Order {
+    pub fn new(mode: OrderMode, seed: Option<u64>, len: usize) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        let mut order = Order {
+            mode,
+            rng,
+            tour: (0..len).collect(),
+        };
+        order.reroll();
+        order
+    }
+
+    pub fn reroll(&mut self) {
+        if let OrderMode::Permutation = self.mode
+            && let Some(rest) = self.tour.get_mut(1..)
+        {
+            rest.shuffle(&mut self.rng);
+        }
+    }
+
+    pub fn first(&self) -> usize {
+        self.tour.first().copied().unwrap_or(0)
+    }
+
+    pub fn last(&self) -> usize {
+        self.tour.last().copied().unwrap_or(0)
+    }
+
+    /// Where Prev goes from `current`, staying put at the start of the tour
+    pub fn before(&self, current: Option<usize>) -> Option<usize> {
+        let Some(current) = current else {
+            return Some(self.last());
+        };
+        let at = self.tour.iter().position(|&i| i == current)?;
+        Some(self.tour[at.saturating_sub(1)])
+    }
+
+    /// Where Next goes from `current`, `None` once past the end of the tour.
+    /// Shuffle never runs out.
+    pub fn after(&mut self, current: Option<usize>) -> Option<usize> {
+        let Some(current) = current else {
+            return Some(self.first());
+        };
+        match self.mode {
+            OrderMode::Shuffle => {
+                let others: Vec<usize> =
+                    self.tour.iter().copied().filter(|&i| i != current).collect();
+                Some(others.choose(&mut self.rng).copied().unwrap_or(current))
+            }
+            OrderMode::Sequential | OrderMode::Permutation => {
+                let at = self.tour.iter().position(|&i| i == current)?;
+                self.tour.get(at + 1).copied()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential_runs_out_at_the_end() {
+        let mut order = Order::new(OrderMode::Sequential, None, 3);
+        assert_eq!(order.after(None), Some(0));
+        assert_eq!(order.after(Some(0)), Some(1));
+        assert_eq!(order.after(Some(2)), None);
+        assert_eq!((order.first(), order.last()), (0, 2));
+    }
+
+    #[test]
+    fn prev_stays_put_at_the_start() {
+        let order = Order::new(OrderMode::Sequential, None, 3);
+        assert_eq!(order.before(Some(2)), Some(1));
+        assert_eq!(order.before(Some(0)), Some(0));
+        assert_eq!(order.before(None), Some(2));
+    }
+
+    #[test]
+    fn permutation_keeps_home_first_and_visits_everything() {
+        let mut order = Order::new(OrderMode::Permutation, Some(7), 6);
+        for _ in 0..5 {
+            assert_eq!(order.first(), 0);
+            let mut tour = vec![order.first()];
+            while let Some(next) = order.after(tour.last().copied()) {
+                tour.push(next);
+            }
+            tour.sort();
+            assert_eq!(tour, (0..6).collect::<Vec<_>>());
+            order.reroll();
+        }
+    }
+
+    #[test]
+    fn a_seed_makes_the_permutation_repeatable() {
+        let tour = |order: &mut Order| {
+            let mut tour = vec![order.first()];
+            while let Some(next) = order.after(tour.last().copied()) {
+                tour.push(next);
+            }
+            tour
+        };
+        let mut first = Order::new(OrderMode::Permutation, Some(42), 8);
+        let mut second = Order::new(OrderMode::Permutation, Some(42), 8);
+        assert_eq!(tour(&mut first), tour(&mut second));
+    }
+
+    #[test]
+    fn shuffle_never_repeats_and_never_runs_out() {
+        let mut order = Order::new(OrderMode::Shuffle, Some(1), 4);
+        let mut at = order.first();
+        for _ in 0..50 {
+            let next = order.after(Some(at)).expect("shuffle goes on forever");
+            assert_ne!(next, at);
+            at = next;
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// What survives a restart, kept as JSON in `state_file`
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Persisted {
+    pub volume: Option<f64>,
+}
+
+impl Persisted {
+    /// A missing or unreadable file starts over from the config
+    pub fn load(path: &str) -> Persisted {
+        let Ok(json) = std::fs::read_to_string(path) else {
+            return Persisted::default();
+        };
+        serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable {path}: {e}");
+            Persisted::default()
+        })
+    }
+
+    pub fn save(&self, path: &str) {
+        // Renamed into place, so a power cut never leaves half a file behind
+        let tmp = format!("{path}.tmp");
+        let saved = serde_json::to_string(self)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&tmp, json))
+            .and_then(|()| std::fs::rename(&tmp, path));
+        if let Err(e) = saved {
+            warn!("Failed to save {path}: {e}");
+        }
+    }
+}
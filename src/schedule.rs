@@ -0,0 +1,21 @@
+use chrono::Local;
+use log::info;
+use tokio::sync::mpsc;
+
+use crate::{Command, Queued};
+use crate::settings::DisplaySchedule;
+use crate::state::Source;
+
+/// Tells playback to power the display down and up at the scheduled times,
+/// straight over the playback channel so it doesn't count as visitor input
+pub async fn run(schedule: DisplaySchedule, playback: mpsc::Sender<Queued>) {
+    loop {
+        let now = Local::now().time();
+        let on = schedule.is_on(now);
+        info!("Display is scheduled {}", if on { "on" } else { "off" });
+        if playback.send(Queued::new(Source::Timer, Command::DisplayPower(on))).await.is_err() {
+            return;
+        }
+        tokio::time::sleep(schedule.until_change(now)).await;
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::Path;
+
+use libmpv::Mpv;
+use libmpv::events::Event;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::logging;
+use crate::settings::{self, Settings};
+use crate::state::State;
+
+/// How many one second waits the test clip gets to start playing
+const LOAD_WAIT_ATTEMPTS: usize = 10;
+
+/// One line of the `selftest` report
+#[derive(Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, outcome: std::result::Result<String, String>) -> Self {
+        let (passed, detail) = match outcome {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Check {
+            name,
+            passed,
+            detail,
+        }
+    }
+}
+
+/// Goes through everything an install depends on, for acceptance after setting up.
+/// Playback carries on meanwhile, the test clip plays in a headless mpv of its own.
+pub async fn run(s: &Settings, state: &Mutex<State>) -> Vec<Check> {
+    let serial = serial_connected(s, &*state.lock().await);
+    let mpv = tokio::task::spawn_blocking(mpv)
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    let config = Settings::new()
+        .map(|_| format!("{} is valid", Settings::config_path()))
+        .map_err(|e| e.to_string());
+    let state_dir = match &s.state_file {
+        Some(file) => writable(Path::new(file).parent().unwrap_or(Path::new("."))),
+        None => Ok("no state file configured".to_string()),
+    };
+
+    vec![
+        Check::new("serial_connected", serial),
+        Check::new("mpv", mpv),
+        Check::new("config", config),
+        Check::new("log_dir", writable(Path::new(&s.log_dir))),
+        Check::new("state_dir", state_dir),
+    ]
+}
+
+/// Every configured port has to be up, the serial tasks own them so nothing gets opened
+/// or probed here. Whether the firmware answers is left to `serial_self_test` at startup.
+fn serial_connected(s: &Settings, state: &State) -> std::result::Result<String, String> {
+    let paths = s.serial_port.paths();
+    if paths.is_empty() {
+        return Ok("no port configured".to_string());
+    }
+    let down: Vec<&str> = paths
+        .iter()
+        .filter(|path| !state.serial_up.get(*path).copied().unwrap_or(false))
+        .map(String::as_str)
+        .collect();
+    if down.is_empty() {
+        Ok(format!("{} connected", paths.join(", ")))
+    } else {
+        Err(format!("not connected: {}", down.join(", ")))
+    }
+}
+
+/// Plays a generated clip and reads its playback time back. Blocking.
+fn mpv() -> std::result::Result<String, String> {
+    let mpv = Mpv::with_initializer(|init| {
+        init.set_property("vo", "null")?;
+        init.set_property("audio", "no")?;
+        Ok(())
+    })
+    .map_err(|e| format!("failed to start mpv: {e:?}"))?;
+
+    let mut ev_ctx = mpv.create_event_context();
+    ev_ctx
+        .disable_deprecated_events()
+        .map_err(|e| format!("no events: {e:?}"))?;
+    mpv.command("loadfile", &[settings::BLANK_SOURCE])
+        .map_err(|e| format!("failed to load the test clip: {e:?}"))?;
+
+    for _ in 0..LOAD_WAIT_ATTEMPTS {
+        match ev_ctx.wait_event(1.) {
+            Some(Ok(Event::PlaybackRestart)) => {
+                return mpv
+                    .get_property::<f64>("playback-time")
+                    .map(|time| format!("test clip playing at {time:.2}s"))
+                    .map_err(|e| format!("no playback-time: {e:?}"));
+            }
+            Some(Err(e)) => return Err(format!("test clip failed: {e:?}")),
+            _ => {}
+        }
+    }
+    Err("timed out waiting for the test clip".to_string())
+}
+
+fn writable(dir: &Path) -> std::result::Result<String, String> {
+    logging::writable(dir)
+        .map(|()| format!("{} is writable", dir.display()))
+        .map_err(|e| format!("{}: {e}", dir.display()))
+}
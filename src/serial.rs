@@ -0,0 +1,299 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::sink::SinkExt;
+use futures::stream::{SplitSink, StreamExt};
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{Mutex, broadcast, mpsc},
+    time::timeout,
+};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::error::{CavempvError, Result};
+use crate::settings::{PressMapping, SerialSelfTest, Settings};
+use crate::state::{Source, State};
+use crate::{ButtonState, Command, LineCodec, Outbound, Probe};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Writes waiting for a slow port before new ones get dropped
+const OUTBOUND_LEN: usize = 16;
+
+type Writer = SplitSink<Framed<SerialStream, LineCodec>, Outbound>;
+
+/// Handed to every port before a restart, each drops its copy once it's written everything out
+pub type Flushed = mpsc::Sender<()>;
+
+/// What a port task talks to the rest of the player through
+pub struct Links {
+    pub intake: mpsc::Sender<(Source, Command)>,
+    pub buttons: broadcast::Receiver<ButtonState>,
+    /// Acks for whichever port the command came in on, the others skip them
+    pub acks: broadcast::Receiver<(usize, u8)>,
+    pub closing: broadcast::Receiver<Flushed>,
+}
+
+/// Opens a port at startup, running the configured round-trip self-test on it.
+/// A failed test only refuses the port when `require_serial` is set.
+pub async fn boot(path: &str, s: &Settings) -> Result<SerialStream> {
+    let mut port = open(path, s.baud_rate as u32).await?;
+
+    if let Some(test) = &s.serial_self_test {
+        if self_test(&mut port, test).await {
+            info!("[{path}] Self-test passed");
+        } else if s.require_serial {
+            return Err(CavempvError::SerialSelfTest {
+                path: path.to_string(),
+                timeout_ms: test.timeout_ms,
+            });
+        } else {
+            warn!("[{path}] Self-test failed, carrying on without confirmation");
+        }
+    }
+
+    Ok(port)
+}
+
+async fn self_test(port: &mut SerialStream, test: &SerialSelfTest) -> bool {
+    let mut frame = BytesMut::new();
+    if LineCodec::default().encode(Probe(test.probe as u8), &mut frame).is_err()
+        || port.write_all(&frame).await.is_err()
+    {
+        return false;
+    }
+
+    let ack = test.ack as u8;
+    let mut buf = [0u8; 64];
+    timeout(Duration::from_millis(test.timeout_ms), async {
+        loop {
+            match port.read(&mut buf).await {
+                Ok(0) | Err(_) => return false,
+                Ok(n) if buf[..n].contains(&ack) => return true,
+                Ok(_) => {}
+            }
+        }
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Where a configured port may be right now: `|` separates fallbacks tried in order,
+/// each a path or a glob such as `/dev/serial/by-id/usb-*`
+fn candidates(spec: &str) -> Vec<String> {
+    spec.split('|')
+        .map(str::trim)
+        .flat_map(|pattern| match glob::glob(pattern) {
+            Ok(matched) if pattern.contains(['*', '?', '[']) => matched
+                .flatten()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect(),
+            _ => vec![pattern.to_string()],
+        })
+        .collect()
+}
+
+/// Opens the first of the candidates `spec` names that works
+pub async fn open(spec: &str, baud_rate: u32) -> Result<SerialStream> {
+    let mut failed = None;
+    for path in candidates(spec) {
+        match open_path(&path, baud_rate).await {
+            Ok(port) => {
+                if path != spec {
+                    info!("[{spec}] Connected on {path}");
+                }
+                return Ok(port);
+            }
+            Err(e) => {
+                warn!("[{spec}] Can't use {path}: {e:?}");
+                failed = Some(e);
+            }
+        }
+    }
+    Err(failed.unwrap_or_else(|| CavempvError::SerialOpen {
+        path: spec.to_string(),
+        source: tokio_serial::Error::new(tokio_serial::ErrorKind::NoDevice, "nothing matches"),
+    }))
+}
+
+async fn open_path(path: &str, baud_rate: u32) -> Result<SerialStream> {
+    let open_failed = |source| CavempvError::SerialOpen {
+        path: path.to_string(),
+        source,
+    };
+    let mut port = tokio_serial::new(path, baud_rate)
+        .open_native_async()
+        .map_err(open_failed)?;
+
+    port.set_exclusive(false).map_err(open_failed)?;
+
+    port.flush().await?;
+
+    Ok(port)
+}
+
+/// Owns the write half, so a slow write never holds up reading
+async fn write_out(path: String, mut writer: Writer, mut outbound: mpsc::Receiver<Outbound>) {
+    while let Some(item) = outbound.recv().await {
+        if let Outbound::Shutdown = item {
+            if let Err(e) = writer.close().await {
+                error!("[{path}] Failed to flush: {e:?}");
+            }
+            return;
+        }
+        if let Err(e) = writer.send(item).await {
+            error!("[{path}] Failed to write: {e:?}");
+            return;
+        }
+    }
+}
+
+/// Feeds commands from one port into the intake and mirrors button states and acks
+/// back to it, reopening the port whenever it goes away.
+/// Stops for good once it's flushed for a restart.
+/// `index` is where the port is in `serial_port`, commands from it carry that.
+pub async fn run(
+    path: String,
+    index: usize,
+    baud_rate: u32,
+    press: PressMapping,
+    mut port: Option<SerialStream>,
+    links: Links,
+    state: Arc<Mutex<State>>,
+) {
+    let Links {
+        intake,
+        mut buttons,
+        mut acks,
+        mut closing,
+    } = links;
+    loop {
+        let connected = match port.take() {
+            Some(connected) => connected,
+            None => match open(&path, baud_rate).await {
+                Ok(connected) => {
+                    info!("[{path}] Reconnected");
+                    connected
+                }
+                Err(e) => {
+                    error!("[{path}] Failed to open: {e:?}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            },
+        };
+
+        // Coalescing only passes on changes, a fresh connection needs telling what's shown now
+        let shown = {
+            let mut state = state.lock().await;
+            state.serial_up.insert(path.clone(), true);
+            if state.buffering {
+                Some(ButtonState::Blinking)
+            } else {
+                state.shown()
+            }
+        };
+        let (writer, mut reader) = LineCodec { press: press.clone() }.framed(connected).split();
+        let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_LEN);
+        if let Some(shown) = shown {
+            outbound.try_send(Outbound::Buttons(shown)).ok();
+        }
+        let mut writing = tokio::spawn(write_out(path.clone(), writer, outbound_rx));
+
+        loop {
+            tokio::select! {
+                line = reader.next() => match line {
+                    // Waiting on a busy intake would stall reading, and the acks with it
+                    Some(Ok(command)) => match intake.try_send((Source::Serial(index), command)) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full((_, dropped))) => {
+                            warn!("[{path}] Commands are piling up, dropped {dropped:?}");
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => {
+                            writing.abort();
+                            return;
+                        }
+                    },
+                    Some(Err(e)) => {
+                        error!("[{path}] Unexpected error: {e:?}");
+                        break;
+                    }
+                    None => break,
+                },
+                button_state = buttons.recv() => match button_state {
+                    Ok(button_state) => match outbound.try_send(Outbound::Buttons(button_state)) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(dropped)) => {
+                            warn!("[{path}] Port is falling behind, dropped {dropped:?}");
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => break,
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("[{path}] Dropped {skipped} button states");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        writing.abort();
+                        return;
+                    }
+                },
+                ack = acks.recv() => match ack {
+                    Ok((to, _)) if to != index => {}
+                    Ok((_, ack)) => match outbound.try_send(Outbound::Ack(ack)) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            warn!("[{path}] Port is falling behind, dropped an ack");
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => break,
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("[{path}] Dropped {skipped} acks");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        writing.abort();
+                        return;
+                    }
+                },
+                flushed = closing.recv() => {
+                    info!("[{path}] Flushing for the restart");
+                    if outbound.send(Outbound::Shutdown).await.is_ok() {
+                        (&mut writing).await.ok();
+                    }
+                    drop(flushed);
+                    return;
+                }
+                _ = &mut writing => break,
+            }
+        }
+        writing.abort();
+        state.lock().await.serial_up.insert(path.clone(), false);
+
+        warn!("[{path}] Disconnected, reconnecting in {RECONNECT_DELAY:?}");
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallbacks_are_tried_in_order() {
+        let spec = "/dev/ttyACM0 | /dev/ttyUSB0";
+        assert_eq!(candidates(spec), ["/dev/ttyACM0", "/dev/ttyUSB0"]);
+    }
+
+    #[test]
+    fn globs_expand_to_what_exists() {
+        let dir = std::env::temp_dir().join(format!("cavempv-candidates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["usb-a", "usb-b", "other"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let at = dir.to_string_lossy();
+        let found = candidates(&format!("{at}/usb-* | {at}/none-* | /dev/ttyS0"));
+        assert_eq!(found, [format!("{at}/usb-a"), format!("{at}/usb-b"), "/dev/ttyS0".into()]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,41 +1,1046 @@
+use std::collections::HashMap;
 use std::env;
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use config::{Config, ConfigError, Environment, File};
-use serde::Deserialize;
+use chrono::{Datelike, NaiveDate, NaiveTime, TimeDelta, Weekday};
 
-#[derive(Debug, Deserialize, Clone)]
+use config::{Config, Environment, File};
+use serde::{Deserialize, Serialize};
+
+use crate::{ButtonState, Command};
+use crate::error::{CavempvError, Result};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[allow(unused)]
 pub struct Settings {
     pub log_dir: String,
-    pub serial_port: String,
+    /// Free space the log and media volumes should keep, old logs get pruned below it
+    pub min_free_mb: Option<u64>,
+    /// Where logs go, console plus daily files when unset
+    pub log_target: Option<LogTarget>,
+    /// Unset or empty runs on the other command sources alone
+    #[serde(default)]
+    pub serial_port: SerialPorts,
     pub baud_rate: usize,
+    pub serial_self_test: Option<SerialSelfTest>,
+    /// Written back to the port a command came in on once playback accepts it
+    pub serial_ack: Option<char>,
+    /// Button state changes closer together than this get merged into the latest
+    pub buttonstate_debounce_ms: Option<u64>,
+    /// What `:short` and `:long` presses from single-button hardware stand for
+    #[serde(default)]
+    pub press_mapping: PressMapping,
+    /// Refuse to start when a port can't be opened or its self-test gets no ack,
+    /// otherwise such a port keeps being retried in the background
+    #[serde(default)]
+    pub require_serial: bool,
     pub sleep_timeout_sec: usize,
+    /// A Next/Prev this soon after Sleep goes back to where Sleep left instead
+    pub sleep_undo_sec: Option<usize>,
+    /// What Sleep does, whether the inactivity timer or a button sent it
+    #[serde(default)]
+    pub sleep_action: SleepAction,
+    /// Pick fragments back up where they were left instead of restarting them
+    #[serde(default)]
+    pub resume_on_return: bool,
+    /// Positions older than this are forgotten
+    pub resume_max_age_sec: Option<u64>,
+    /// Advance through the playlist on its own when idle for this long
+    pub attract_advance_sec: Option<usize>,
+    /// Back to the first fragment after this long without input, but without sleeping
+    pub reset_to_home_sec: Option<usize>,
+    /// mpv plays the whole playlist back to back by itself, Next and Prev just step it
+    #[serde(default)]
+    pub continuous: bool,
+    #[serde(default)]
+    pub on_task_panic: OnTaskPanic,
+    #[serde(default)]
+    pub restart_mode: RestartMode,
+    /// Only explicit commands change anything, playback drops whatever timers inject,
+    /// the display schedule included
+    #[serde(default)]
+    pub manual_only: bool,
+    /// Played unless `default_playlist` picks one of `playlists`,
+    /// switched back to as [`DEFAULT_PLAYLIST`]
+    #[serde(default)]
     pub playlist: Vec<Fragment>,
+    /// Named playlists to switch between at runtime
+    #[serde(default)]
+    pub playlists: HashMap<String, Vec<Fragment>>,
+    /// Can be left out with a single named playlist and no plain `playlist`
+    pub default_playlist: Option<String>,
+    /// Hash every media file at startup against the `media.sha256` next to it
+    #[serde(default)]
+    pub verify_media: bool,
+    /// Refuse to start over problems that would otherwise only be logged
+    #[serde(default)]
+    pub strict_config: bool,
+    /// Content pack whose `manifest.toml` replaces `playlist`
+    pub pack_dir: Option<String>,
+    #[serde(default)]
+    pub order: OrderMode,
+    /// Makes the permutation repeatable across restarts
+    pub order_seed: Option<u64>,
+    /// Base for relative media paths, defaults to the working directory
+    pub media_root: Option<String>,
+    /// Shared clips that fragments can name without a path
+    pub intro_dir: Option<String>,
+    pub fadeout_dir: Option<String>,
     pub rotation_deg: Option<i64>,
+    /// Screen mpv goes fullscreen on, for machines with several outputs
+    pub fs_screen: Option<i64>,
+    /// Output name mpv should end up on, as it reports in `display-names`
+    pub fs_screen_name: Option<String>,
+    /// Re-pin `fs_screen` when mpv landed elsewhere
+    #[serde(default)]
+    pub fs_screen_retry: bool,
+    /// mpv's own config directory, where the profiles live
+    pub mpv_config_dir: Option<String>,
+    /// Applied at startup
+    pub mpv_profile: Option<String>,
+    /// mpv's own messages from this level up go into our log, `warn` or `v` for example
+    pub mpv_log_level: Option<String>,
+    pub control_socket: Option<String>,
+    /// Skip the token check on the unix socket, relying on its file permissions
+    #[serde(default)]
+    pub control_socket_exempt: bool,
+    pub control_tcp: Option<String>,
+    /// Named pipe taking the same commands as the control socket, without replies
+    pub command_fifo: Option<String>,
+    /// Named command sequences, run with `macro <name>`
+    #[serde(default)]
+    pub macros: HashMap<String, Vec<Command>>,
+    /// Pause between macro steps
+    pub macro_step_ms: Option<u64>,
+    pub control_token: Option<String>,
+    /// Address for the page with the basic actions, behind `control_token` too
+    pub web_ui: Option<String>,
+    /// Lets the control interface drop out of fullscreen for calibration
+    #[serde(default)]
+    pub allow_fullscreen_toggle: bool,
+    /// Lets the control interface read any mpv property with `getprop`
+    #[serde(default)]
+    pub maintenance_enabled: bool,
+    /// Properties `setprop` may change, nothing else is writable
+    #[serde(default)]
+    pub maintenance_writable: Vec<String>,
+    /// How long `identify` shows the hostname and blinks, 5s by default
+    pub identify_ms: Option<u64>,
+    pub initial_volume: Option<u8>,
+    pub max_volume: Option<u8>,
+    /// Where whatever survives a restart is kept
+    pub state_file: Option<String>,
+    /// Every cut gets a JSON line here, apart from the general log
+    pub transition_log: Option<String>,
+    /// Volume changes are saved to `state_file` and picked up again at startup
+    #[serde(default)]
+    pub persist_volume: bool,
+    /// Limits for `speed`, 0.25 to 4 unless set
+    pub min_speed: Option<f64>,
+    pub max_speed: Option<f64>,
+    /// Keep a changed speed across transitions instead of going back to 1
+    #[serde(default)]
+    pub preserve_speed: bool,
+    pub splash: Option<String>,
+    /// Logo kept over every fragment
+    pub watermark: Option<Watermark>,
+    /// Holds the splash, or black, this long after mpv starts before the first fragment,
+    /// for displays that drop the first seconds while they wake up
+    pub startup_delay_ms: Option<u64>,
+    pub error_osd: Option<ErrorOsd>,
+    /// Photosensitivity guards, transitions over the rate get delayed
+    pub max_transitions_per_sec: Option<f32>,
+    #[serde(default)]
+    pub force_fade_on_cut: bool,
+    /// Precise seeks, and fadeouts picked from the playback time snapped to a frame
+    pub fadeout_precise: Option<bool>,
+    /// Fadeouts are skipped for this long after one played, against double cuts
+    pub post_fadeout_lockout_ms: Option<u64>,
+    #[serde(default)]
+    pub end_action: EndAction,
+    #[serde(default)]
+    pub prev_action: PrevAction,
+    /// Played once by `outro_then_sleep` before returning to the first fragment
+    pub wrap_outro: Option<String>,
+    /// Looped by `idle` until the next command
+    pub idle_clip: Option<String>,
+    /// Played on every Next that has no fadeout of its own
+    pub transition_clip: Option<String>,
+    pub screen_off_cmd: Option<String>,
+    pub screen_on_cmd: Option<String>,
+    /// Powers the display off overnight through the screen commands
+    pub display_schedule: Option<DisplaySchedule>,
+    /// Let commands turn the display on during the scheduled off window
+    #[serde(default)]
+    pub allow_wake_when_off: bool,
+    /// Keeps several instances on the same fragment index
+    pub sync_group: Option<SyncGroup>,
+}
+
+/// What Next does once it runs past the last fragment
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EndAction {
+    #[default]
+    Wrap,
+    LoopLast,
+    OutroThenSleep,
+    Idle,
+    DisplayOff,
+}
+
+/// What happens when a background task panics
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnTaskPanic {
+    /// Starts the task over
+    #[default]
+    Restart,
+    /// Exits for the service manager to restart the player
+    Shutdown,
+}
+
+/// How the `restart` command starts over
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartMode {
+    /// Replaces the process with a fresh one, no service manager needed
+    #[default]
+    Exec,
+    /// Exits for the service manager to restart the player
+    Exit,
+}
+
+/// What Sleep does
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SleepAction {
+    /// Back to the first fragment, what Sleep always did
+    #[default]
+    RewindToHome,
+    /// Display off through `screen_off_cmd`, black without one, keeping the cursor
+    BlankScreen,
+    /// Loops `idle_clip` until the next command, keeping the cursor
+    IdleFragment,
+    /// Pauses on a clean frame
+    PauseHold,
+}
+
+/// What Prev does
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrevAction {
+    /// Previous in playlist order
+    MovePrev,
+    /// Plays the current fragment again after its fadeout, what Prev always did
+    #[default]
+    Restart,
+    Home,
+    /// Back to the fragment visited before this one
+    History,
+    /// Ignored altogether
+    Noop,
+}
+
+/// How Next walks the playlist
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderMode {
+    #[default]
+    Sequential,
+    Shuffle,
+    Permutation,
+}
+
+/// `EndAction` resolved against the clips it needs
+#[derive(Debug, PartialEq)]
+pub enum EndStep<'a> {
+    Wrap { outro: Option<&'a str> },
+    StayOnLast,
+    Idle(&'a str),
+    DisplayOff,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogTarget {
+    Console,
+    File,
+    Journald,
+    Syslog,
+}
+
+/// Local times the display goes off and comes back, the off window may span midnight
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DisplaySchedule {
+    pub off_at: NaiveTime,
+    pub on_at: NaiveTime,
+}
+
+impl DisplaySchedule {
+    pub fn is_on(&self, time: NaiveTime) -> bool {
+        let off = if self.off_at <= self.on_at {
+            self.off_at <= time && time < self.on_at
+        } else {
+            time >= self.off_at || time < self.on_at
+        };
+        !off
+    }
+
+    /// Time left until the display should switch again
+    pub fn until_change(&self, time: NaiveTime) -> Duration {
+        let next = if self.is_on(time) { self.off_at } else { self.on_at };
+        let mut wait = next - time;
+        if wait <= TimeDelta::zero() {
+            wait += TimeDelta::days(1);
+        }
+        wait.to_std().unwrap_or(Duration::from_secs(60))
+    }
+}
+
+/// The leader multicasts its transitions to `addr`, followers in the same group apply them
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SyncGroup {
+    pub name: String,
+    pub role: SyncRole,
+    pub addr: Option<SocketAddrV4>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncRole {
+    Leader,
+    Follower,
+}
+
+/// Slow drift over a looping static, from the first values of each pair to the second
+/// over `duration_sec` and back again. Zoom is mpv's log2 scale, pans are fractions
+/// of the picture.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PanZoom {
+    #[serde(default)]
+    pub zoom: (f64, f64),
+    #[serde(default)]
+    pub pan_x: (f64, f64),
+    #[serde(default)]
+    pub pan_y: (f64, f64),
+    pub duration_sec: f64,
+}
+
+impl PanZoom {
+    /// Zoom and pans `elapsed` seconds in
+    pub fn at(&self, elapsed: f64) -> (f64, f64, f64) {
+        let phase = if self.duration_sec > 0.0 {
+            (elapsed / self.duration_sec) % 2.0
+        } else {
+            0.0
+        };
+        let t = if phase > 1.0 { 2.0 - phase } else { phase };
+        let lerp = |(from, to): (f64, f64)| from + (to - from) * t;
+        (lerp(self.zoom), lerp(self.pan_x), lerp(self.pan_y))
+    }
+}
+
+/// Corner the watermark sits in
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+/// Image overlaid on the picture through an mpv video filter
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Watermark {
+    pub image: String,
+    #[serde(default)]
+    pub position: Corner,
+    /// 0 to 1, opaque unless set
+    pub opacity: Option<f32>,
+    /// Of the image's own size
+    pub scale: Option<f32>,
+    /// Pixels from the edges, 20 unless set
+    pub margin: Option<u32>,
+}
+
+impl Watermark {
+    /// The `vf` entry drawing it, labelled so it can be swapped out
+    pub fn filter(&self) -> String {
+        let margin = self.margin.unwrap_or(20);
+        let (x, y) = match self.position {
+            Corner::TopLeft => (format!("{margin}"), format!("{margin}")),
+            Corner::TopRight => (format!("W-w-{margin}"), format!("{margin}")),
+            Corner::BottomLeft => (format!("{margin}"), format!("H-h-{margin}")),
+            Corner::BottomRight => (format!("W-w-{margin}"), format!("H-h-{margin}")),
+        };
+        format!(
+            "@watermark:lavfi=[movie='{}',scale=iw*{}:-1,format=rgba,\
+             colorchannelmixer=aa={}[wm];[in][wm]overlay={x}:{y}[out]]",
+            self.image.replace('\'', "\\'"),
+            self.scale.unwrap_or(1.0),
+            self.opacity.unwrap_or(1.0),
+        )
+    }
+}
+
+/// Shown over the picture while playback fails, instead of plain black
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErrorOsd {
+    pub text: String,
+    pub font_size: Option<u32>,
+    /// Any of mpv's color notations, `#FFFFFF` for example
+    pub color: Option<String>,
+}
+
+/// Probe sent to the firmware at boot, expecting `ack` back within `timeout_ms`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SerialSelfTest {
+    pub probe: char,
+    pub ack: char,
+    pub timeout_ms: u64,
+}
+
+/// Commands for the two press durations a single button can tell apart
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PressMapping {
+    pub short: Command,
+    pub long: Command,
+}
+
+impl Default for PressMapping {
+    fn default() -> Self {
+        PressMapping {
+            short: Command::Next,
+            long: Command::Sleep,
+        }
+    }
+}
+
+/// A single port or several panels controlling the same player.
+/// Each can list fallbacks separated by `|` and use globs, see `serial::open`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum SerialPorts {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for SerialPorts {
+    fn default() -> Self {
+        SerialPorts::Many(Vec::new())
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl SerialPorts {
+    pub fn paths(&self) -> &[String] {
+        match self {
+            SerialPorts::One(path) if path.is_empty() => &[],
+            SerialPorts::One(path) => std::slice::from_ref(path),
+            SerialPorts::Many(paths) => paths,
+        }
+    }
+}
+
+/// Static that shows plain black instead of a file
+pub const BLANK: &str = "@black";
+pub const BLANK_SOURCE: &str = "av://lavfi:color=c=black";
+
+/// What the plain `playlist` goes by among the named ones
+pub const DEFAULT_PLAYLIST: &str = "default";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[allow(unused)]
 pub struct Fragment {
     pub intro: Option<String>,
     #[serde(rename = "static")]
     pub static_: String,
     pub fadeout: Option<Vec<Fadeout>>,
+    pub loop_start_sec: Option<f64>,
+    pub loop_end_sec: Option<f64>,
+    /// Ignore Next/Prev until the intro is over
+    pub lock_during_intro: Option<bool>,
+    /// Hold the last frame instead of blanking when a clip ends
+    pub keep_open: Option<bool>,
+    /// Relay pins held high while the fragment plays, needs the `gpio` feature
+    pub gpio_high: Option<Vec<u8>>,
+    /// External subtitles shown over the static
+    pub sub_file: Option<String>,
+    /// Audio track to play, by id or language
+    pub audio_track: Option<String>,
+    /// Overrides the global `resume_on_return`
+    pub resume_on_return: Option<bool>,
+    /// Applied while the fragment plays, on top of the global profile
+    pub mpv_profile: Option<String>,
+    /// Signalled while the fragment plays instead of what its files would suggest,
+    /// an intro that can't be skipped still shows as locked
+    pub button_state: Option<ButtonState>,
+    /// Plays muted or not whatever the runtime mute says
+    pub mute: Option<bool>,
+    /// Moves on by itself once the static's audio stays silent this long, not with `manual_only`
+    pub silence_ms: Option<u64>,
+    /// Comes up from black over this long once the fragment's first file shows
+    pub fade_in_ms: Option<u32>,
+    /// Animated while the static loops, undone once it's left
+    pub pan_zoom: Option<PanZoom>,
+    /// On-screen label shown when the fragment starts, for 5s unless set
+    pub caption: Option<String>,
+    pub caption_duration_sec: Option<f32>,
+    /// Shown in listings, the static's file name otherwise
+    pub name: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// How long a `@black` static holds before moving on, 3s unless set.
+    /// With `manual_only` it holds until the next command instead.
+    pub blank_sec: Option<f32>,
+    /// `false` keeps the inactivity timeout from sleeping while this plays
+    pub allow_sleep: Option<bool>,
+    /// Wherever playback lands, it passes over the fragment outside these dates, both included
+    pub active_from: Option<NaiveDate>,
+    pub active_until: Option<NaiveDate>,
+    /// Only played on these days, `Mon` or `Monday` for example
+    pub active_days: Option<Vec<Weekday>>,
+    /// Moves on after this long without input while this plays, over `attract_advance_sec`
+    pub idle_advance_sec: Option<u64>,
+    /// Sleeps after this long without input while this plays, over `sleep_timeout_sec`
+    pub idle_sleep_sec: Option<u64>,
 }
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[allow(unused)]
 pub struct Fadeout {
+    /// Seconds from the start
     pub before: Option<f32>,
+    /// Fraction of the clip's duration
+    pub before_pct: Option<f32>,
+    /// Seconds before the end of the clip
+    pub before_from_end: Option<f32>,
     pub video: String,
 }
 
+impl Fadeout {
+    /// Playback time up to which this fadeout applies, `None` if it has no limit
+    pub fn threshold(&self, duration: f32) -> Option<f32> {
+        self.before
+            .or_else(|| self.before_pct.map(|pct| pct * duration))
+            .or_else(|| self.before_from_end.map(|from_end| duration - from_end))
+    }
+}
+
+/// `playback_time` on the nearest frame, a cut about to wrap counts from the next loop
+pub fn align_to_frame(playback_time: f32, duration: f32, fps: f32) -> f32 {
+    if fps <= 0.0 {
+        return playback_time;
+    }
+    let snapped = (playback_time * fps).round() / fps;
+    if duration > 0.0 && snapped >= duration {
+        0.0
+    } else {
+        snapped
+    }
+}
+
+/// A content pack's manifest, media paths are relative to the pack
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    playlist: Vec<Fragment>,
+}
+
+pub fn load_pack(dir: &Path) -> Result<Vec<Fragment>> {
+    let root = env::current_dir()?.join(dir);
+    let manifest: Manifest = Config::builder()
+        .add_source(File::from(root.join("manifest.toml")))
+        .build()?
+        .try_deserialize()?;
+    let mut playlist = manifest.playlist;
+    for fragment in &mut playlist {
+        fragment.rebase(&root, None, None)?;
+    }
+    validate(&playlist)?;
+    Ok(playlist)
+}
+
+fn validate(playlist: &[Fragment]) -> Result<()> {
+    if playlist.is_empty() {
+        return Err(CavempvError::PlaylistEmpty);
+    }
+    if let Some(missing) = playlist
+        .iter()
+        .filter_map(|fragment| fragment.sub_file.as_ref())
+        .find(|sub_file| !Path::new(sub_file).is_file())
+    {
+        return Err(CavempvError::MediaMissing(missing.clone()));
+    }
+    Ok(())
+}
+
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        let config_name = env::var("CONFIG_FILE").unwrap_or_else(|_| "main".into());
+    pub fn new() -> Result<Self> {
         let s = Config::builder()
-            .add_source(File::with_name(&format!("cfg/{config_name}")))
+            .add_source(File::with_name(&Settings::config_path()))
             .add_source(Environment::with_prefix("detect"))
             .build()?;
-        s.try_deserialize()
+        let mut settings: Settings = s.try_deserialize()?;
+        settings.resolve_media()?;
+        for playlist in settings.playlists.values() {
+            validate(playlist)?;
+        }
+        settings.settle_playlist()?;
+        if let Some(dir) = &settings.pack_dir {
+            settings.playlist = load_pack(Path::new(dir))?;
+        }
+        validate(&settings.playlist)?;
+        Ok(settings)
+    }
+
+    /// Registers the plain `playlist` under [`DEFAULT_PLAYLIST`] so it can be switched back to,
+    /// then swaps in whichever named one `default_playlist` picks or the only one there is
+    fn settle_playlist(&mut self) -> Result<()> {
+        if !self.playlist.is_empty() {
+            let plain = self.playlist.clone();
+            self.playlists.entry(DEFAULT_PLAYLIST.to_string()).or_insert(plain);
+        } else if self.default_playlist.is_none() {
+            let mut names = self.playlists.keys();
+            if let (Some(only), None) = (names.next(), names.next()) {
+                self.default_playlist = Some(only.clone());
+            } else if !self.playlists.is_empty() {
+                return Err(CavempvError::PlaylistAmbiguous(self.playlists.len()));
+            }
+        }
+        if let Some(name) = &self.default_playlist {
+            self.playlist = self.named_playlist(name)?;
+        }
+        Ok(())
+    }
+
+    pub fn named_playlist(&self, name: &str) -> Result<Vec<Fragment>> {
+        self.playlists
+            .get(name)
+            .cloned()
+            .ok_or_else(|| CavempvError::PlaylistUnknown(name.to_string()))
+    }
+
+    /// Config file the settings come from, without its extension
+    pub fn config_path() -> String {
+        let config_name = env::var("CONFIG_FILE").unwrap_or_else(|_| "main".into());
+        format!("cfg/{config_name}")
+    }
+
+    /// The resolved settings with the control token hidden, for printing
+    pub fn masked(&self) -> Settings {
+        let mut masked = self.clone();
+        if masked.control_token.is_some() {
+            masked.control_token = Some("********".to_string());
+        }
+        masked
+    }
+
+    /// Where relative media paths are resolved from
+    pub fn media_dir(&self) -> Result<PathBuf> {
+        let cwd = env::current_dir()?;
+        Ok(match &self.media_root {
+            Some(root) => cwd.join(root),
+            None => cwd,
+        })
+    }
+
+    /// Every media file the playlists play, each once
+    pub fn media_files(&self) -> Vec<String> {
+        let fragments = self.playlist.iter().chain(self.playlists.values().flatten());
+        let files: std::collections::BTreeSet<&String> = fragments
+            .flat_map(|fragment| {
+                let fadeouts = fragment.fadeout.iter().flatten().map(|fadeout| &fadeout.video);
+                fragment.intro.iter().chain([&fragment.static_]).chain(fadeouts)
+            })
+            .filter(|path| Path::new(path).is_file())
+            .collect();
+        files.into_iter().cloned().collect()
+    }
+
+    /// Makes every relative media path absolute against `media_root`,
+    /// which itself is taken relative to the working directory
+    fn resolve_media(&mut self) -> Result<()> {
+        let root = self.media_dir()?;
+        let rebase = |path: &mut String| *path = resolve(&root, path);
+
+        let intro_dir = self.intro_dir.as_deref().map(|dir| root.join(dir));
+        let fadeout_dir = self.fadeout_dir.as_deref().map(|dir| root.join(dir));
+        for fragment in self.playlist.iter_mut().chain(self.playlists.values_mut().flatten()) {
+            fragment.rebase(&root, intro_dir.as_deref(), fadeout_dir.as_deref())?;
+        }
+        self.splash.iter_mut().for_each(rebase);
+        self.wrap_outro.iter_mut().for_each(rebase);
+        self.idle_clip.iter_mut().for_each(rebase);
+        self.transition_clip.iter_mut().for_each(rebase);
+        if let Some(watermark) = &mut self.watermark {
+            rebase(&mut watermark.image);
+        }
+        Ok(())
+    }
+
+    pub fn end_step(&self) -> EndStep<'_> {
+        match self.end_action {
+            EndAction::Wrap => EndStep::Wrap { outro: None },
+            EndAction::LoopLast => EndStep::StayOnLast,
+            EndAction::OutroThenSleep => EndStep::Wrap {
+                outro: self.wrap_outro.as_deref(),
+            },
+            EndAction::Idle => match &self.idle_clip {
+                Some(clip) => EndStep::Idle(clip),
+                None => EndStep::Wrap { outro: None },
+            },
+            EndAction::DisplayOff => EndStep::DisplayOff,
+        }
+    }
+
+    pub fn resumes(&self, fragment: &Fragment) -> bool {
+        fragment.resume_on_return.unwrap_or(self.resume_on_return)
+    }
+
+    pub fn clamp_speed(&self, speed: f64) -> f64 {
+        speed.clamp(self.min_speed.unwrap_or(0.25), self.max_speed.unwrap_or(4.0))
+    }
+
+    /// Keeps any requested volume within what the amplifier can take
+    pub fn clamp_volume(&self, volume: f64) -> f64 {
+        volume.clamp(0.0, f64::from(self.max_volume.unwrap_or(100)))
+    }
+}
+
+/// Which part of a fragment a playing file is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Intro,
+    Static,
+    Fadeout,
+}
+
+impl Fragment {
+    /// Bare intro and fadeout names are looked up in the shared dirs first
+    fn rebase(
+        &mut self,
+        root: &Path,
+        intro_dir: Option<&Path>,
+        fadeout_dir: Option<&Path>,
+    ) -> Result<()> {
+        let rebase = |path: &mut String| *path = resolve(root, path);
+        if self.static_ == BLANK {
+            self.static_ = BLANK_SOURCE.to_string();
+        } else {
+            rebase(&mut self.static_);
+        }
+        self.sub_file.iter_mut().for_each(rebase);
+        if let Some(intro) = &mut self.intro {
+            *intro = resolve_shared(root, intro_dir, intro)?;
+        }
+        for fadeout in self.fadeout.iter_mut().flatten() {
+            fadeout.video = resolve_shared(root, fadeout_dir, &fadeout.video)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the fragment is within its active window on `day`
+    pub fn active_on(&self, day: NaiveDate) -> bool {
+        self.active_from.is_none_or(|from| day >= from)
+            && self.active_until.is_none_or(|until| day <= until)
+            && self
+                .active_days
+                .as_ref()
+                .is_none_or(|days| days.contains(&day.weekday()))
+    }
+
+    pub fn is_blank(&self) -> bool {
+        self.static_ == BLANK_SOURCE
+    }
+
+    pub fn display_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            Path::new(&self.static_)
+                .file_stem()
+                .map_or_else(|| self.static_.clone(), |stem| stem.to_string_lossy().into_owned())
+        })
+    }
+
+    pub fn role_of(&self, filename: &str) -> Option<Role> {
+        if is_file(&self.static_, filename) {
+            Some(Role::Static)
+        } else if self
+            .intro
+            .as_deref()
+            .is_some_and(|intro| is_file(intro, filename))
+        {
+            Some(Role::Intro)
+        } else if self
+            .fadeout
+            .iter()
+            .flatten()
+            .any(|fadeout| is_file(&fadeout.video, filename))
+        {
+            Some(Role::Fadeout)
+        } else {
+            None
+        }
+    }
+
+    /// Segment of the static to loop over, only when both ends are configured
+    pub fn ab_loop(&self) -> Option<(f64, f64)> {
+        self.loop_start_sec.zip(self.loop_end_sec)
+    }
+}
+
+/// Joins a relative path onto `root`, absolute paths are left untouched
+pub fn resolve(root: &Path, path: &str) -> String {
+    root.join(path).to_string_lossy().into_owned()
+}
+
+/// A bare file name is taken from `dir` when it's there, anything else resolves onto `root`.
+/// A bare name found in neither is refused.
+fn resolve_shared(root: &Path, dir: Option<&Path>, path: &str) -> Result<String> {
+    let bare = Path::new(path).components().count() == 1;
+    match dir.filter(|_| bare) {
+        Some(dir) if dir.join(path).is_file() => Ok(resolve(dir, path)),
+        Some(_) if !root.join(path).is_file() => Err(CavempvError::MediaMissing(path.to_string())),
+        _ => Ok(resolve(root, path)),
+    }
+}
+
+/// Whether mpv's `filename` property refers to the configured path
+pub fn is_file(path: &str, filename: &str) -> bool {
+    Path::new(path)
+        .file_name()
+        .is_some_and(|name| name == filename)
+}
+
+/// Maps a playing file back to its fragment, preferring the current one
+/// since the same clip may be shared by several fragments
+pub fn locate(playlist: &[Fragment], current: usize, filename: &str) -> Option<(usize, Role)> {
+    playlist
+        .get(current)
+        .and_then(|fragment| fragment.role_of(filename))
+        .map(|role| (current, role))
+        .or_else(|| {
+            playlist.iter().enumerate().find_map(|(index, fragment)| {
+                fragment.role_of(filename).map(|role| (index, role))
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The required fields plus whatever the test cares about
+    pub(crate) fn settings(extra: serde_json::Value) -> Settings {
+        let mut config = serde_json::json!({
+            "log_dir": "logs",
+            "baud_rate": 9600,
+            "sleep_timeout_sec": 60,
+        });
+        if let (Some(config), Some(extra)) = (config.as_object_mut(), extra.as_object()) {
+            config.extend(extra.clone());
+        }
+        serde_json::from_value(config).expect("a valid test config")
+    }
+
+    fn fragment(file: &str) -> serde_json::Value {
+        serde_json::json!({ "static": file })
+    }
+
+    #[test]
+    fn plain_playlist_can_be_switched_back_to() {
+        let mut s = settings(serde_json::json!({
+            "playlist": [fragment("a.mp4")],
+            "playlists": { "other": [fragment("b.mp4")] },
+        }));
+        s.settle_playlist().unwrap();
+        assert_eq!(s.named_playlist(DEFAULT_PLAYLIST).unwrap()[0].static_, "a.mp4");
+        assert_eq!(s.playlist[0].static_, "a.mp4");
+    }
+
+    #[test]
+    fn default_playlist_picks_a_named_one() {
+        let mut s = settings(serde_json::json!({
+            "playlist": [fragment("a.mp4")],
+            "playlists": { "other": [fragment("b.mp4")] },
+            "default_playlist": "other",
+        }));
+        s.settle_playlist().unwrap();
+        assert_eq!(s.playlist[0].static_, "b.mp4");
+    }
+
+    #[test]
+    fn a_single_named_playlist_is_the_default() {
+        let mut s = settings(serde_json::json!({
+            "playlists": { "only": [fragment("b.mp4")] },
+        }));
+        s.settle_playlist().unwrap();
+        assert_eq!(s.default_playlist.as_deref(), Some("only"));
+        assert_eq!(s.playlist[0].static_, "b.mp4");
+    }
+
+    #[test]
+    fn several_named_playlists_need_a_default() {
+        let mut s = settings(serde_json::json!({
+            "playlists": { "one": [fragment("a.mp4")], "two": [fragment("b.mp4")] },
+        }));
+        assert!(matches!(s.settle_playlist(), Err(CavempvError::PlaylistAmbiguous(2))));
+    }
+
+    #[test]
+    fn sleep_rewinds_to_home_by_default() {
+        let s = settings(serde_json::json!({}));
+        assert_eq!(s.sleep_action, SleepAction::RewindToHome);
+    }
+
+    #[test]
+    fn sleep_action_takes_every_variant() {
+        for (name, action) in [
+            ("rewind_to_home", SleepAction::RewindToHome),
+            ("blank_screen", SleepAction::BlankScreen),
+            ("idle_fragment", SleepAction::IdleFragment),
+            ("pause_hold", SleepAction::PauseHold),
+        ] {
+            let s = settings(serde_json::json!({ "sleep_action": name }));
+            assert_eq!(s.sleep_action, action, "{name}");
+        }
+    }
+
+    #[test]
+    fn end_action_resolves_against_its_clips() {
+        let s = settings(serde_json::json!({ "end_action": "loop_last" }));
+        assert_eq!(s.end_step(), EndStep::StayOnLast);
+        let s = settings(serde_json::json!({
+            "end_action": "outro_then_sleep",
+            "wrap_outro": "outro.mp4",
+        }));
+        assert_eq!(s.end_step(), EndStep::Wrap { outro: Some("outro.mp4") });
+        let s = settings(serde_json::json!({ "end_action": "idle", "idle_clip": "idle.mp4" }));
+        assert_eq!(s.end_step(), EndStep::Idle("idle.mp4"));
+    }
+
+    #[test]
+    fn idle_without_a_clip_wraps() {
+        let s = settings(serde_json::json!({ "end_action": "idle" }));
+        assert_eq!(s.end_step(), EndStep::Wrap { outro: None });
+        let s = settings(serde_json::json!({}));
+        assert_eq!(s.end_step(), EndStep::Wrap { outro: None });
+    }
+
+    #[test]
+    fn frames_snap_to_the_nearest_one() {
+        assert_eq!(align_to_frame(1.01, 10.0, 25.0), 1.0);
+        assert_eq!(align_to_frame(1.03, 10.0, 25.0), 1.04);
+        assert_eq!(align_to_frame(1.03, 10.0, 0.0), 1.03);
+    }
+
+    #[test]
+    fn a_cut_about_to_wrap_counts_from_the_next_loop() {
+        assert_eq!(align_to_frame(9.99, 10.0, 25.0), 0.0);
+    }
+
+    fn schedule(off_at: &str, on_at: &str) -> DisplaySchedule {
+        serde_json::from_value(serde_json::json!({ "off_at": off_at, "on_at": on_at }))
+            .expect("a valid schedule")
+    }
+
+    fn time(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+
+    #[test]
+    fn display_is_off_within_the_window() {
+        let day = schedule("12:00:00", "14:00:00");
+        assert!(day.is_on(time(11, 59)));
+        assert!(!day.is_on(time(12, 0)));
+        assert!(day.is_on(time(14, 0)));
+        let night = schedule("22:00:00", "07:00:00");
+        assert!(!night.is_on(time(23, 0)));
+        assert!(!night.is_on(time(3, 0)));
+        assert!(night.is_on(time(12, 0)));
+    }
+
+    #[test]
+    fn display_waits_for_the_next_switch_across_midnight() {
+        let night = schedule("22:00:00", "07:00:00");
+        assert_eq!(night.until_change(time(21, 0)), Duration::from_secs(3600));
+        assert_eq!(night.until_change(time(23, 0)), Duration::from_secs(8 * 3600));
+    }
+
+    #[test]
+    fn pan_zoom_drifts_there_and_back() {
+        let pan_zoom: PanZoom = serde_json::from_value(serde_json::json!({
+            "zoom": [0.0, 1.0],
+            "pan_x": [0.0, -0.5],
+            "duration_sec": 10.0,
+        }))
+        .unwrap();
+        assert_eq!(pan_zoom.at(0.0), (0.0, 0.0, 0.0));
+        assert_eq!(pan_zoom.at(5.0), (0.5, -0.25, 0.0));
+        assert_eq!(pan_zoom.at(10.0), (1.0, -0.5, 0.0));
+        assert_eq!(pan_zoom.at(15.0), (0.5, -0.25, 0.0));
+        assert_eq!(pan_zoom.at(20.0), (0.0, 0.0, 0.0));
+    }
+
+    fn fadeout(limit: serde_json::Value) -> Fadeout {
+        let mut fadeout = serde_json::json!({ "video": "out.mp4" });
+        if let (Some(fadeout), Some(limit)) = (fadeout.as_object_mut(), limit.as_object()) {
+            fadeout.extend(limit.clone());
+        }
+        serde_json::from_value(fadeout).unwrap()
+    }
+
+    #[test]
+    fn fadeout_threshold_takes_the_first_limit_set() {
+        assert_eq!(fadeout(serde_json::json!({ "before": 3.0 })).threshold(20.0), Some(3.0));
+        let pct = fadeout(serde_json::json!({ "before_pct": 0.25 }));
+        assert_eq!(pct.threshold(20.0), Some(5.0));
+        let from_end = fadeout(serde_json::json!({ "before_from_end": 4.0 }));
+        assert_eq!(from_end.threshold(20.0), Some(16.0));
+        let both = fadeout(serde_json::json!({ "before": 3.0, "before_pct": 0.25 }));
+        assert_eq!(both.threshold(20.0), Some(3.0));
+        assert_eq!(fadeout(serde_json::json!({})).threshold(20.0), None);
+    }
+
+    #[test]
+    fn shared_clips_come_from_their_dir_first() {
+        let root = env::temp_dir().join(format!("cavempv-resolve-{}", std::process::id()));
+        let dir = root.join("shared");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("both.mp4"), b"").unwrap();
+        std::fs::write(root.join("both.mp4"), b"").unwrap();
+        std::fs::write(root.join("root.mp4"), b"").unwrap();
+
+        let shared = |path| resolve_shared(&root, Some(dir.as_path()), path);
+        assert_eq!(shared("both.mp4").unwrap(), resolve(&dir, "both.mp4"));
+        assert_eq!(shared("root.mp4").unwrap(), resolve(&root, "root.mp4"));
+        assert_eq!(shared("sub/nested.mp4").unwrap(), resolve(&root, "sub/nested.mp4"));
+        assert!(matches!(shared("neither.mp4"), Err(CavempvError::MediaMissing(_))));
+        let unshared = resolve_shared(&root, None, "neither.mp4").unwrap();
+        assert_eq!(unshared, resolve(&root, "neither.mp4"));
+
+        std::fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn volume_stays_within_zero_and_max() {
+        let s = settings(serde_json::json!({ "max_volume": 80 }));
+        assert_eq!(s.clamp_volume(-5.0), 0.0);
+        assert_eq!(s.clamp_volume(45.0), 45.0);
+        assert_eq!(s.clamp_volume(85.0), 80.0);
+    }
+
+    #[test]
+    fn volume_caps_at_100_by_default() {
+        let s = settings(serde_json::json!({}));
+        assert_eq!(s.clamp_volume(100.0 + 5.0), 100.0);
+    }
+
+    #[test]
+    fn volume_can_go_past_100_when_allowed() {
+        let s = settings(serde_json::json!({ "max_volume": 150 }));
+        assert_eq!(s.clamp_volume(125.0 + 5.0), 130.0);
+        assert_eq!(s.clamp_volume(200.0), 150.0);
     }
 }
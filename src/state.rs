@@ -0,0 +1,165 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::settings::Fragment;
+use crate::{ButtonState, Command};
+
+const HISTORY_LEN: usize = 32;
+
+/// Where a command came from
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Carries which of the configured ports it came in on
+    Serial(usize),
+    Socket,
+    Timer,
+    /// The leader of the sync group
+    Sync,
+    Fifo,
+    /// A step of a running macro
+    Macro,
+    Web,
+    #[cfg(feature = "test-hooks")]
+    Test,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    pub timestamp_ms: u128,
+    pub source: Source,
+    pub command: Command,
+}
+
+/// Player state shared between the tasks, kept in sync with what mpv reports
+#[derive(Debug, Default, Serialize)]
+pub struct State {
+    pub paused: bool,
+    /// Playlist index of the fragment being played
+    pub current: usize,
+    /// Playing an intro that Next/Prev must not skip
+    pub intro_locked: bool,
+    /// Commands playback acted on, oldest first
+    pub history: VecDeque<HistoryEntry>,
+    /// What the playing file asks the hardware to show
+    pub automatic_buttons: Option<ButtonState>,
+    /// Maintenance override, wins over the automatic state until cleared
+    pub forced_buttons: Option<ButtonState>,
+    /// mpv is stalled on the cache, the hardware blinks meanwhile
+    pub buffering: bool,
+    /// Inactivity Sleep is suppressed until released
+    pub held_awake: bool,
+    /// Muted at runtime, signalled in place of the automatic state
+    pub muted: bool,
+    /// Below `min_free_mb`, optional files like screenshots aren't written
+    pub disk_low: bool,
+    /// Whether each serial port is currently open, by path
+    pub serial_up: HashMap<String, bool>,
+    /// Transition waiting for its file to show, by command sequence number
+    #[serde(skip)]
+    pub awaiting: Option<(u64, Instant)>,
+    /// File to seek in once mpv has loaded it, for fragments resumed where they were left
+    #[serde(skip)]
+    pub seek_on_load: Option<(String, f64)>,
+    /// From the command entering playback's queue to the filename changing
+    pub latency_ms: Option<u128>,
+    /// The playlist being played, swapped wholesale by content packs
+    #[serde(skip)]
+    pub playlist: Arc<Vec<Fragment>>,
+}
+
+impl State {
+    pub fn record(&mut self, source: Source, command: &Command) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(HistoryEntry {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis())
+                .unwrap_or(0),
+            source,
+            command: command.clone(),
+        });
+    }
+
+    /// Remembers the automatic state and returns what should actually be signalled
+    pub fn signal(&mut self, automatic: ButtonState) -> ButtonState {
+        self.automatic_buttons = Some(automatic.clone());
+        self.shown().unwrap_or(automatic)
+    }
+
+    /// Sets or clears the override, returning what to signal right away if known
+    pub fn force(&mut self, forced: Option<ButtonState>) -> Option<ButtonState> {
+        self.forced_buttons = forced;
+        self.shown()
+    }
+
+    /// What the hardware should show once buffering is over,
+    /// a locked intro still shows as locked while muted
+    pub fn shown(&self) -> Option<ButtonState> {
+        let locked = self
+            .automatic_buttons
+            .clone()
+            .filter(|automatic| *automatic == ButtonState::Locked);
+        self.forced_buttons
+            .clone()
+            .or(locked)
+            .or_else(|| self.muted.then_some(ButtonState::Muted))
+            .or_else(|| self.automatic_buttons.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn automatic_state_shows_by_default() {
+        let mut state = State::default();
+        assert_eq!(state.shown(), None);
+        assert_eq!(state.signal(ButtonState::Both), ButtonState::Both);
+        assert_eq!(state.shown(), Some(ButtonState::Both));
+    }
+
+    #[test]
+    fn muting_shows_over_the_automatic_state() {
+        let mut state = State {
+            muted: true,
+            ..State::default()
+        };
+        assert_eq!(state.signal(ButtonState::Both), ButtonState::Muted);
+    }
+
+    #[test]
+    fn a_locked_intro_still_shows_as_locked_while_muted() {
+        let mut state = State {
+            muted: true,
+            ..State::default()
+        };
+        assert_eq!(state.signal(ButtonState::Locked), ButtonState::Locked);
+    }
+
+    #[test]
+    fn forcing_wins_until_cleared() {
+        let mut state = State::default();
+        state.signal(ButtonState::Locked);
+        assert_eq!(state.force(Some(ButtonState::Off)), Some(ButtonState::Off));
+        assert_eq!(state.signal(ButtonState::StartOnly), ButtonState::Off);
+        assert_eq!(state.force(None), Some(ButtonState::StartOnly));
+    }
+
+    #[test]
+    fn history_keeps_the_latest() {
+        let mut state = State::default();
+        for index in 0..HISTORY_LEN + 3 {
+            state.record(Source::Timer, &Command::GoTo(index));
+        }
+        assert_eq!(state.history.len(), HISTORY_LEN);
+        assert!(matches!(state.history[0].command, Command::GoTo(3)));
+    }
+}
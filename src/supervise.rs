@@ -0,0 +1,122 @@
+use std::future::Future;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use tokio::task::{AbortHandle, JoinHandle};
+
+use crate::settings::{OnTaskPanic, RestartMode};
+
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Tells the service manager the exit was asked for and worth a restart
+const RESTART_EXIT_CODE: i32 = 75;
+
+/// Cleanup for the exits that skip unwinding, where nothing else gets dropped
+static ON_EXIT: Mutex<Vec<Box<dyn FnMut() + Send>>> = Mutex::new(Vec::new());
+
+/// Takes the running task down with the supervisor when that gets aborted
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs the task `make` builds, building a fresh one whenever it panics
+/// unless `on_panic` asks for a shutdown instead.
+/// Aborting the returned handle stops the task for good.
+pub fn spawn<F, Fut>(name: &'static str, on_panic: OnTaskPanic, mut make: F) -> AbortHandle
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let supervisor = tokio::spawn(async move {
+        loop {
+            let mut running = AbortOnDrop(tokio::spawn(make()));
+            match (&mut running.0).await {
+                Ok(()) => {
+                    warn!("{name} task is done");
+                    return;
+                }
+                Err(e) if e.is_panic() => {
+                    error!("{name} task panicked");
+                    if let OnTaskPanic::Shutdown = on_panic {
+                        shut_down();
+                    }
+                    info!("Restarting {name} task in {RESTART_DELAY:?}");
+                    tokio::time::sleep(RESTART_DELAY).await;
+                }
+                Err(_) => return,
+            }
+        }
+    });
+    supervisor.abort_handle()
+}
+
+/// For tasks that own something they can't get back, a panic always shuts down
+pub fn once<Fut>(name: &'static str, task: Fut)
+where
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        match tokio::spawn(task).await {
+            Ok(()) => warn!("{name} task is done"),
+            Err(e) if e.is_panic() => {
+                error!("{name} task panicked and can't be restarted");
+                shut_down();
+            }
+            Err(_) => {}
+        }
+    });
+}
+
+/// Runs `hook` before any restart or shutdown from here, in the order they were added
+pub fn on_exit(hook: impl FnMut() + Send + 'static) {
+    ON_EXIT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Box::new(hook));
+}
+
+fn run_exit_hooks() {
+    let mut hooks = ON_EXIT.lock().unwrap_or_else(PoisonError::into_inner);
+    hooks.iter_mut().for_each(|hook| hook());
+}
+
+/// Starts the player over as a new process, in place or through the service manager
+pub fn restart(mode: RestartMode) -> ! {
+    run_exit_hooks();
+    if let RestartMode::Exec = mode {
+        match std::env::current_exe() {
+            Ok(exe) => {
+                info!("Restarting in place");
+                log::logger().flush();
+                let e = std::os::unix::process::CommandExt::exec(
+                    std::process::Command::new(exe).args(std::env::args_os().skip(1)),
+                );
+                error!("Failed to restart in place: {e}, exiting instead");
+            }
+            Err(e) => error!("Can't find the binary to restart: {e}, exiting instead"),
+        }
+    }
+    info!("Exiting to be restarted");
+    log::logger().flush();
+    std::process::exit(RESTART_EXIT_CODE)
+}
+
+/// Exits with a failure for the service manager to restart the whole player
+fn shut_down() -> ! {
+    error!("Shutting down");
+    run_exit_hooks();
+    log::logger().flush();
+    std::process::exit(1)
+}
+
+/// Exits cleanly as asked, by SIGTERM or the like
+pub fn terminate() -> ! {
+    info!("Terminated, shutting down");
+    run_exit_hooks();
+    log::logger().flush();
+    std::process::exit(0)
+}
@@ -0,0 +1,79 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::Command;
+use crate::error::Result;
+use crate::settings::SyncGroup;
+use crate::state::Source;
+
+const DEFAULT_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 42), 4242);
+
+/// What the leader multicasts after every transition
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+    group: String,
+    index: usize,
+}
+
+/// Multicasts the leader's position, nobody needs to be listening
+pub struct Leader {
+    socket: UdpSocket,
+    group: String,
+    addr: SocketAddrV4,
+}
+
+impl Leader {
+    pub async fn bind(sync: &SyncGroup) -> Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let addr = sync.addr.unwrap_or(DEFAULT_ADDR);
+        info!("Leading sync group {} on {addr}", sync.name);
+        Ok(Leader {
+            socket,
+            group: sync.name.clone(),
+            addr,
+        })
+    }
+
+    pub async fn announce(&self, index: usize) {
+        let announcement = Announcement {
+            group: self.group.clone(),
+            index,
+        };
+        let Ok(packet) = serde_json::to_vec(&announcement) else {
+            return;
+        };
+        if let Err(e) = self.socket.send_to(&packet, self.addr).await {
+            error!("Failed to announce fragment {index} to the sync group: {e:?}");
+        }
+    }
+}
+
+/// Turns the leader's announcements into `GoTo`s, ignoring other groups
+pub async fn follow(sync: SyncGroup, intake: mpsc::Sender<(Source, Command)>) -> Result<()> {
+    let addr = sync.addr.unwrap_or(DEFAULT_ADDR);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port())).await?;
+    socket.join_multicast_v4(*addr.ip(), Ipv4Addr::UNSPECIFIED)?;
+    info!("Following sync group {} on {addr}", sync.name);
+
+    let mut buf = [0u8; 512];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        match serde_json::from_slice::<Announcement>(&buf[..n]) {
+            Ok(announcement) if announcement.group == sync.name => {
+                if intake
+                    .send((Source::Sync, Command::GoTo(announcement.index)))
+                    .await
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Ignoring malformed sync packet from {peer}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::{Path, PathBuf};
+
+use libmpv::Mpv;
+use libmpv::events::Event;
+use log::info;
+
+use crate::error::{CavempvError, Result};
+use crate::settings::Fragment;
+
+/// How many one second waits a single clip gets to produce its frame
+const FRAME_WAIT_ATTEMPTS: usize = 30;
+
+/// Dumps the first frame of every fragment's static into `dir`, returning the written files.
+/// Blocking, each clip gets its own headless mpv so the live instance is left alone.
+pub fn generate(playlist: &[Fragment], dir: &Path) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    playlist
+        .iter()
+        .enumerate()
+        .map(|(index, fragment)| thumbnail(index, &fragment.static_, dir))
+        .collect()
+}
+
+fn thumbnail(index: usize, video: &str, dir: &Path) -> Result<PathBuf> {
+    let scratch = dir.join(format!(".{index}"));
+    std::fs::create_dir_all(&scratch)?;
+    let scratch_dir = scratch.to_string_lossy().into_owned();
+
+    let mpv = Mpv::with_initializer(|init| {
+        init.set_property("vo", "image")?;
+        init.set_property("vo-image-format", "png")?;
+        init.set_property("vo-image-outdir", scratch_dir.as_str())?;
+        init.set_property("frames", 1i64)?;
+        init.set_property("audio", "no")?;
+        Ok(())
+    })
+    .map_err(|e| CavempvError::MpvInit(format!("{e:?}")))?;
+
+    let mut ev_ctx = mpv.create_event_context();
+    ev_ctx
+        .disable_deprecated_events()
+        .map_err(|e| CavempvError::Thumbnail(format!("no events: {e:?}")))?;
+
+    mpv.command("loadfile", &[video])
+        .map_err(|e| CavempvError::MpvCommand {
+            command: format!("loadfile {video}"),
+            reason: format!("{e:?}"),
+        })?;
+
+    let mut finished = false;
+    for _ in 0..FRAME_WAIT_ATTEMPTS {
+        if let Some(Ok(Event::EndFile(_))) = ev_ctx.wait_event(1.) {
+            finished = true;
+            break;
+        }
+    }
+    if !finished {
+        return Err(CavempvError::Thumbnail(format!(
+            "timed out waiting for a frame of {video}"
+        )));
+    }
+
+    let stem = Path::new(video)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let thumbnail = dir.join(format!("{index:03}-{stem}.png"));
+    std::fs::rename(scratch.join("00000001.png"), &thumbnail)?;
+    std::fs::remove_dir_all(&scratch)?;
+
+    info!("Thumbnail for {video} written to {}", thumbnail.display());
+    Ok(thumbnail)
+}
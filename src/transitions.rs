@@ -0,0 +1,57 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::Command;
+use crate::state::Source;
+
+/// A log past this size moves to `<path>.1`, replacing the one there
+const MAX_LEN: u64 = 1024 * 1024;
+
+/// One cut as `transition_log` records it
+#[derive(Serialize)]
+pub struct Transition<'a> {
+    pub from: Option<usize>,
+    pub to: usize,
+    pub source: Source,
+    pub command: &'a Command,
+    pub fadeout: bool,
+    pub intro: bool,
+    /// The static the cut lands on
+    pub file: &'a str,
+}
+
+/// Appends one JSON line, logging instead of failing
+pub fn record(path: &str, transition: &Transition) {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        timestamp_ms: u128,
+        #[serde(flatten)]
+        transition: &'a Transition<'a>,
+    }
+
+    if std::fs::metadata(path).is_ok_and(|meta| meta.len() > MAX_LEN)
+        && let Err(e) = std::fs::rename(path, format!("{path}.1"))
+    {
+        warn!("Failed to rotate {path}: {e}");
+    }
+    let line = Line {
+        timestamp_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis())
+            .unwrap_or(0),
+        transition,
+    };
+    let written = serde_json::to_string(&line)
+        .map_err(std::io::Error::from)
+        .and_then(|json| {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{json}")
+        });
+    if let Err(e) = written {
+        warn!("Failed to record the transition in {path}: {e}");
+    }
+}
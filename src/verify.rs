@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+/// Manifest in `sha256sum` format, looked up in the directory of every file
+const MANIFEST: &str = "media.sha256";
+
+/// Checks every file against the manifest next to it and returns the ones that don't match.
+/// Files the manifest doesn't mention are only reported.
+pub fn mismatched(paths: &[String]) -> Vec<String> {
+    let mut manifests: HashMap<&Path, HashMap<String, String>> = HashMap::new();
+    let mut mismatched = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        let manifest = manifests.entry(dir).or_insert_with(|| load(&dir.join(MANIFEST)));
+        let Some(expected) = manifest.get(&*name.to_string_lossy()) else {
+            warn!("{} isn't in its {MANIFEST}, not verifying it", path.display());
+            continue;
+        };
+        match hash(path) {
+            Ok(actual) if &actual == expected => {}
+            Ok(actual) => {
+                warn!("{} hashes to {actual}, expected {expected}", path.display());
+                mismatched.push(path.display().to_string());
+            }
+            Err(e) => {
+                warn!("Failed to hash {}: {e}", path.display());
+                mismatched.push(path.display().to_string());
+            }
+        }
+    }
+    info!("Verified {} media files, {} mismatched", paths.len(), mismatched.len());
+    mismatched
+}
+
+/// File names to hex digests, empty when there's no manifest
+fn load(manifest: &Path) -> HashMap<String, String> {
+    let Ok(lines) = std::fs::read_to_string(manifest) else {
+        return HashMap::new();
+    };
+    lines
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(digest, name)| {
+            // `sha256sum -b` marks binary files with a leading asterisk
+            let name = name.trim_start().trim_start_matches('*');
+            (name.to_string(), digest.to_lowercase())
+        })
+        .collect()
+}
+
+fn hash(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,116 @@
+use log::{error, info};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::Command;
+use crate::control::{Control, token_matches};
+use crate::error::Result;
+use crate::state::Source;
+
+/// The whole UI, nothing gets fetched from elsewhere
+const PAGE: &str = include_str!("web.html");
+
+/// Status line, content type and body of a reply
+type Reply = (&'static str, &'static str, String);
+
+/// Tiny HTTP page with the basic actions, for staff without a terminal.
+/// With a token configured it has to come as `Authorization: Bearer <token>`,
+/// or as `?token=<token>` when opening the page.
+pub async fn serve(control: Control, addr: String) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Web UI listening on http://{addr}");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(handle(control.clone(), stream));
+    }
+}
+
+async fn handle(control: Control, stream: TcpStream) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    let Ok(Some(request)) = lines.next_line().await else {
+        return;
+    };
+    let mut bearer = None;
+    while let Ok(Some(header)) = lines.next_line().await {
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("authorization")
+        {
+            bearer = value.trim().strip_prefix("Bearer ").map(str::to_string);
+        }
+    }
+
+    let (status, content_type, body) = match request.split(' ').collect::<Vec<_>>()[..] {
+        [method, target, _] => {
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            let token = bearer.or_else(|| {
+                query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("token="))
+                    .map(str::to_string)
+            });
+            respond(&control, method, path, token.as_deref()).await
+        }
+        _ => ("400 Bad Request", "text/plain", "malformed request".to_string()),
+    };
+
+    let reply = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(e) = write.write_all(reply.as_bytes()).await {
+        error!("Failed to answer on the web UI: {e:?}");
+    }
+}
+
+async fn respond(control: &Control, method: &str, path: &str, token: Option<&str>) -> Reply {
+    if let Some(expected) = control.token.as_deref()
+        && !token_matches(token, expected)
+    {
+        return ("401 Unauthorized", "text/plain", "unauthorized".to_string());
+    }
+
+    let command = match (method, path.strip_prefix("/goto/")) {
+        ("GET", None) if path == "/" => return ("200 OK", "text/html", page(control).await),
+        ("POST", Some(index)) => match index.parse() {
+            Ok(index) => Command::GoTo(index),
+            Err(e) => return ("400 Bad Request", "text/plain", e.to_string()),
+        },
+        ("POST", None) => match path {
+            "/next" => Command::Next,
+            "/prev" => Command::Prev,
+            "/sleep" => Command::Sleep,
+            _ => return ("404 Not Found", "text/plain", "not found".to_string()),
+        },
+        _ => return ("404 Not Found", "text/plain", "not found".to_string()),
+    };
+    match control.intake.send((Source::Web, command)).await {
+        Ok(()) => ("200 OK", "text/plain", "ok".to_string()),
+        Err(e) => ("503 Service Unavailable", "text/plain", e.to_string()),
+    }
+}
+
+/// The page with the name of whatever is playing filled in
+async fn page(control: &Control) -> String {
+    let name = {
+        let state = control.state.lock().await;
+        state
+            .playlist
+            .get(state.current)
+            .map_or_else(String::new, |fragment| fragment.display_name())
+    };
+    let escaped = name
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+    PAGE.replace("@FRAGMENT@", &escaped)
+}
@@ -0,0 +1,23 @@
+//! Drives commands through the `test-hooks` injection point,
+//! with a channel standing in for the player's intake
+
+use cavempv::{Command, hooks};
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn injected_commands_reach_the_intake_in_order() {
+    assert!(!hooks::inject_command(Command::Next).await, "nothing installed yet");
+
+    let (intake, mut player) = mpsc::channel(8);
+    hooks::install(intake);
+    assert!(hooks::inject_command(Command::Next).await);
+    assert!(hooks::inject_command(Command::GoTo(1)).await);
+
+    let (_, first) = player.recv().await.expect("the Next");
+    assert!(matches!(first, Command::Next));
+    let (_, second) = player.recv().await.expect("the GoTo");
+    assert!(matches!(second, Command::GoTo(1)));
+
+    drop(player);
+    assert!(!hooks::inject_command(Command::Next).await, "the player is gone");
+}